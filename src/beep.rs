@@ -1,11 +1,20 @@
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
 use std::time::Duration;
+use std::sync::Arc;
 use pitch_calc::Hz;
 use rodio::Source;
 use rodio;
-use std;
+
+/// How far a bent note moves towards its new frequency each sample, so that bends glide over a
+/// few milliseconds rather than clicking.
+const BEND_GLIDE: f32 = 0.005;
+const SAMPLE_RATE: u32 = 48000;
 
 pub struct Beeper {
     endpoint: rodio::Endpoint,
+    /// Bumped to silence everything started before it changed.
+    generation: Arc<AtomicUsize>,
+    bends: Bends,
 }
 
 impl Beeper {
@@ -14,30 +23,165 @@ impl Beeper {
 
         Self {
             endpoint,
+            generation: Arc::new(AtomicUsize::new(0)),
+            bends: Bends::new(),
         }
     }
 
-    pub fn beep<H: Into<Hz>>(&self, frequency: H, duration: Duration, volume: f32) {
+    pub fn beep<H: Into<Hz>>(&self, frequency: H, duration: Duration, volume: f32, channel: u8) {
+        let frequency = frequency.into().0;
+        let source = SquareWave::new(frequency, self.bends.clone(), channel);
+        let source = source.amplify(volume).take_duration(duration);
+        let source = self.stoppable(source, Arc::new(AtomicBool::new(false)));
+        rodio::play_raw(&self.endpoint, source);
+    }
+
+    /// Starts a note that keeps sounding until the returned voice is stopped or dropped.
+    pub fn start<H: Into<Hz>>(&self, frequency: H, volume: f32, channel: u8) -> Voice {
         let frequency = frequency.into().0;
-        let source = SquareWave::new(frequency as u32);
-        let source = source.amplify(volume).repeat_infinite().take_duration(duration);
+        let stopped = Arc::new(AtomicBool::new(false));
+        let source = self.stoppable(SquareWave::new(frequency, self.bends.clone(), channel).amplify(volume), stopped.clone());
         rodio::play_raw(&self.endpoint, source);
+
+        Voice {
+            stopped,
+        }
+    }
+
+    /// Bends every note on a channel, both those sounding and those yet to start.
+    pub fn bend(&self, channel: u8, semitones: f32) {
+        self.bends.set(channel, 2.0f32.powf(semitones / 12.0));
+    }
+
+    /// Stops every beep and voice currently sounding, and straightens every bend.
+    pub fn silence(&self) {
+        self.generation.fetch_add(1, Ordering::SeqCst);
+        self.bends.reset();
+    }
+
+    fn stoppable<S>(&self, source: S, stopped: Arc<AtomicBool>) -> Stoppable<S> {
+        Stoppable {
+            source,
+            stopped,
+            generation: self.generation.clone(),
+            started_in: self.generation.load(Ordering::SeqCst),
+        }
+    }
+}
+
+pub struct Voice {
+    stopped: Arc<AtomicBool>,
+}
+
+impl Voice {
+    pub fn stop(&self) {
+        self.stopped.store(true, Ordering::SeqCst);
+    }
+}
+
+impl Drop for Voice {
+    fn drop(&mut self) {
+        self.stop();
+    }
+}
+
+/// Plays the wrapped source until told to stop by the corresponding `Voice`, or until the
+/// `Beeper` that started it is silenced.
+struct Stoppable<S> {
+    source: S,
+    stopped: Arc<AtomicBool>,
+    generation: Arc<AtomicUsize>,
+    started_in: usize,
+}
+
+impl<S: Iterator<Item = f32>> Iterator for Stoppable<S> {
+    type Item = f32;
+
+    #[inline]
+    fn next(&mut self) -> Option<f32> {
+        if self.stopped.load(Ordering::Relaxed) || self.generation.load(Ordering::Relaxed) != self.started_in {
+            None
+        } else {
+            self.source.next()
+        }
+    }
+}
+
+impl<S: Source<Item = f32>> Source for Stoppable<S> {
+    #[inline]
+    fn current_frame_len(&self) -> Option<usize> {
+        self.source.current_frame_len()
+    }
+
+    #[inline]
+    fn channels(&self) -> u16 {
+        self.source.channels()
+    }
+
+    #[inline]
+    fn samples_rate(&self) -> u32 {
+        self.source.samples_rate()
+    }
+
+    #[inline]
+    fn total_duration(&self) -> Option<Duration> {
+        None
+    }
+}
+
+/// How much each MIDI channel's notes are bent, as frequency ratios, shared between the `Beeper`
+/// and every note it plays so that bends reach notes already sounding.
+#[derive(Clone, Debug)]
+struct Bends(Arc<Vec<AtomicUsize>>);
+
+impl Bends {
+    fn new() -> Self {
+        Bends(Arc::new((0..16).map(|_| AtomicUsize::new(1.0f32.to_bits() as usize)).collect()))
+    }
+
+    /// MIDI channels count from 1.
+    fn index(channel: u8) -> usize {
+        (channel.wrapping_sub(1) & 0x0f) as usize
+    }
+
+    fn ratio(&self, channel: u8) -> f32 {
+        f32::from_bits(self.0[Self::index(channel)].load(Ordering::Relaxed) as u32)
+    }
+
+    fn set(&self, channel: u8, ratio: f32) {
+        self.0[Self::index(channel)].store(ratio.to_bits() as usize, Ordering::Relaxed);
+    }
+
+    fn reset(&self) {
+        for channel in 1..17 {
+            self.set(channel, 1.0);
+        }
     }
 }
 
 #[derive(Clone, Debug)]
 pub struct SquareWave {
     freq: f32,
-    num_sample: usize,
+    /// How far through the current cycle the wave is, from 0 to 1, so that changing frequency
+    /// part way through doesn't jump.
+    phase: f32,
+    bends: Bends,
+    channel: u8,
+    /// The bend actually applied, which glides towards the channel's bend.
+    ratio: f32,
 }
 
 impl SquareWave {
-    /// The frequency of the sine.
     #[inline]
-    pub fn new(freq: u32) -> Self {
+    fn new(freq: f32, bends: Bends, channel: u8) -> Self {
+        let ratio = bends.ratio(channel);
+
         Self {
-            freq: freq as f32,
-            num_sample: 0,
+            freq,
+            phase: 0.0,
+            bends,
+            channel,
+            ratio,
         }
     }
 }
@@ -47,12 +191,13 @@ impl Iterator for SquareWave {
 
     #[inline]
     fn next(&mut self) -> Option<f32> {
-        self.num_sample = self.num_sample.wrapping_add(1);
+        let target = self.bends.ratio(self.channel);
+        self.ratio += (target - self.ratio) * BEND_GLIDE;
 
-        let value = 2.0 * std::f32::consts::PI * self.freq * self.num_sample as f32 / 48000.0;
-        let sin = value.sin();
+        self.phase += self.freq * self.ratio / SAMPLE_RATE as f32;
+        self.phase -= self.phase.floor();
 
-        Some(if sin >= 0.0 { 1.0 } else { -1.0 })
+        Some(if self.phase < 0.5 { 1.0 } else { -1.0 })
     }
 }
 
@@ -69,7 +214,7 @@ impl Source for SquareWave {
 
     #[inline]
     fn samples_rate(&self) -> u32 {
-        48000
+        SAMPLE_RATE
     }
 
     #[inline]
@@ -1,16 +1,20 @@
+use clock::{Clock, ClockSample, SyncedClock};
+use player::{Player, Action};
 use convert_duration::*;
-use packet::Packet;
-use beep::Beeper;
+use packet::{Packet, Capabilities, Waveform, PROTOCOL_VERSION};
+use multicast::MulticastReceiver;
+use connection::Codec;
 
-use std::time::Duration;
-use std::net::TcpStream;
+use std::time::{Duration, Instant};
+use std::net::{TcpStream, SocketAddrV4};
 use std::thread::sleep;
 use std;
 
-use bincode::{serialize_into, deserialize_from};
 use pitch_calc::{Hz, LetterOctave};
 use clap::ArgMatches;
 
+const CLOCK_SYNC_EXCHANGES: usize = 8;
+
 pub fn client(matches: &ArgMatches) {
     let forever: bool = matches.is_present("forever");
 
@@ -34,6 +38,14 @@ pub fn client(matches: &ArgMatches) {
 
 fn client_impl(matches: &ArgMatches) -> Result<(), Box<std::error::Error>> {
     let target = matches.value_of("target").unwrap();
+    let codec = Codec::new(matches.is_present("checksums"));
+    let name = matches.value_of("name").unwrap();
+    let max_polyphony: u32 = matches.value_of("max polyphony").unwrap().parse()
+        .map_err(|_| "invalid max polyphony, must be a whole number")?;
+    let lowest_frequency: f32 = matches.value_of("lowest frequency").unwrap().parse()
+        .map_err(|_| "invalid lowest frequency, must be a floating point number")?;
+    let highest_frequency: f32 = matches.value_of("highest frequency").unwrap().parse()
+        .map_err(|_| "invalid highest frequency, must be a floating point number")?;
 
     println!("connecting to {}...", target);
     let client = loop {
@@ -44,23 +56,59 @@ fn client_impl(matches: &ArgMatches) -> Result<(), Box<std::error::Error>> {
     };
 
     println!("sending client info...");
-    let info = Packet::ClientInfo;
-    serialize_into(&client, &info)?;
+    let info = Packet::ClientInfo {
+        protocol_version: PROTOCOL_VERSION,
+        name: name.to_string(),
+        capabilities: Capabilities {
+            max_polyphony,
+            waveforms: vec![Waveform::Square],
+            lowest_frequency,
+            highest_frequency,
+        },
+    };
+    codec.send(&client, &info)?;
+
+    let uid = match codec.recv(&client)? {
+        Packet::Accept { uid } => {
+            println!("accepted by server as {:?}", uid);
+            uid
+        },
+        Packet::Reject { reason } => return Err(format!("rejected by server: {}", reason).into()),
+        packet => return Err(format!("unexpected packet during handshake: {:?}", packet).into()),
+    };
 
-    let beeper = Beeper::new();
+    println!("synchronising clock...");
+    let clock = synchronise_clock(&client, codec)?;
+    println!("clock offset from server: {}ns", clock.offset());
+
+    let player = Player::new();
+
+    let mut multicast = None;
 
     println!("awaiting commands...");
     loop {
-        let packet: Packet = deserialize_from(&client)?;
+        let packet = codec.recv(&client)?;
+
+        if play_packet(&packet, &player, &clock) {
+            continue;
+        }
 
         match &packet {
-            &Packet::PlayNote { duration, frequency, volume } => {
-                let frequency = Hz(frequency);
-                let duration = nanoseconds_to_duration(duration);
-                beeper.beep(frequency, duration, volume);
-                let LetterOctave(letter, octave) = frequency.to_letter_octave();
-                let duration_ms = (duration_to_seconds(duration) * 1000f64) as u64;
-                println!("beep [{:4} {}] for {:04}ms (volume={:0.2})", format!("{:?},", letter), octave, duration_ms, volume);
+            &Packet::UseMulticast { ref group } => {
+                let group: SocketAddrV4 = group.parse()
+                    .map_err(|_| format!("server sent invalid multicast group: {}", group))?;
+                println!("listening for notes on multicast group {}", group);
+
+                let player = player.clone();
+                // replacing any previous receiver drops, and so stops, it
+                multicast = Some(MulticastReceiver::start(group, uid, codec, move |packet| {
+                    if play_packet(&packet, &player, &clock) == false {
+                        println!("unhandled multicast packet: {:?}", packet);
+                    }
+                })?);
+            },
+            &Packet::Ping { server_time } => {
+                codec.send(&client, &Packet::Pong { server_time })?;
             },
             &Packet::TerminateAfter(duration) => {
                 println!("terminating after {}ns", duration);
@@ -72,5 +120,99 @@ fn client_impl(matches: &ArgMatches) -> Result<(), Box<std::error::Error>> {
         }
     }
 
+    drop(multicast);
+
     Ok(())
 }
+
+/// Schedules packets that carry notes to play, returning false for any other packet.
+fn play_packet(packet: &Packet, player: &Player, clock: &SyncedClock) -> bool {
+    match packet {
+        &Packet::PlayNote { start_time, duration, frequency, volume, channel } => {
+            let frequency = Hz(frequency);
+            let duration = nanoseconds_to_duration(duration);
+            player.schedule(clock.local_instant(start_time), Action::Beep {
+                frequency,
+                duration,
+                volume,
+                channel,
+            });
+            let LetterOctave(letter, octave) = frequency.to_letter_octave();
+            let duration_ms = (duration_to_seconds(duration) * 1000f64) as u64;
+            println!("beep [{:4} {}] for {:04}ms (volume={:0.2})", format!("{:?},", letter), octave, duration_ms, volume);
+        },
+        &Packet::NoteOn { id, start_time, frequency, volume, channel } => {
+            let frequency = Hz(frequency);
+            player.schedule(clock.local_instant(start_time), Action::NoteOn {
+                id,
+                frequency,
+                volume,
+                channel,
+            });
+            let LetterOctave(letter, octave) = frequency.to_letter_octave();
+            println!("note on  [{:4} {}] #{} (volume={:0.2})", format!("{:?},", letter), octave, id, volume);
+        },
+        &Packet::NoteOff { id, end_time } => {
+            player.schedule(clock.local_instant(end_time), Action::NoteOff {
+                id,
+            });
+            println!("note off #{}", id);
+        },
+        &Packet::NoteBatch { start_time, ref notes } => {
+            for note in notes.iter() {
+                player.schedule(clock.local_instant(start_time + note.start_offset), Action::Beep {
+                    frequency: Hz(note.frequency),
+                    duration: nanoseconds_to_duration(note.duration),
+                    volume: note.volume,
+                    channel: note.channel,
+                });
+            }
+            println!("batch of {} note(s)", notes.len());
+        },
+        &Packet::PitchBend { start_time, channel, semitones } => {
+            player.schedule(clock.local_instant(start_time), Action::PitchBend {
+                channel,
+                semitones,
+            });
+            println!("bend channel {} by {:+0.2} semitones", channel, semitones);
+        },
+        &Packet::Silence => {
+            player.schedule(Instant::now(), Action::Silence);
+            println!("silence");
+        },
+        _ => return false,
+    }
+
+    true
+}
+
+fn synchronise_clock(client: &TcpStream, codec: Codec) -> Result<SyncedClock, Box<std::error::Error>> {
+    let clock = Clock::new();
+    let mut samples = Vec::new();
+
+    for _ in 0..CLOCK_SYNC_EXCHANGES {
+        codec.send(client, &Packet::ClockSyncRequest {
+            client_send_time: clock.now(),
+        })?;
+
+        let packet = codec.recv(client)?;
+        let client_receive_time = clock.now();
+
+        match packet {
+            Packet::ClockSyncResponse { client_send_time, server_receive_time, server_send_time } => {
+                samples.push(ClockSample {
+                    client_send_time,
+                    server_receive_time,
+                    server_send_time,
+                    client_receive_time,
+                });
+            },
+            packet => return Err(format!("unexpected packet during clock sync: {:?}", packet).into()),
+        }
+    }
+
+    codec.send(client, &Packet::ClockSyncComplete)?;
+
+    SyncedClock::from_samples(clock, &samples)
+        .ok_or_else(|| "no clock sync samples gathered".into())
+}
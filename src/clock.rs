@@ -0,0 +1,88 @@
+use std::time::Instant;
+
+use convert_duration::*;
+
+/// A monotonic clock counting nanoseconds since it was created. The server's clock defines the
+/// shared timeline that scheduled packets are stamped against.
+#[derive(Copy, Clone, Debug)]
+pub struct Clock {
+    epoch: Instant,
+}
+
+impl Clock {
+    pub fn new() -> Self {
+        Self {
+            epoch: Instant::now(),
+        }
+    }
+
+    pub fn now(&self) -> u64 {
+        self.timestamp(Instant::now())
+    }
+
+    pub fn timestamp(&self, instant: Instant) -> u64 {
+        if instant > self.epoch {
+            duration_to_nanoseconds(instant - self.epoch)
+        } else {
+            0
+        }
+    }
+
+    pub fn instant(&self, timestamp: u64) -> Instant {
+        self.epoch + nanoseconds_to_duration(timestamp)
+    }
+}
+
+/// The four timestamps gathered by one NTP-style request/response exchange.
+#[derive(Copy, Clone, Debug)]
+pub struct ClockSample {
+    pub client_send_time: u64,
+    pub server_receive_time: u64,
+    pub server_send_time: u64,
+    pub client_receive_time: u64,
+}
+
+impl ClockSample {
+    pub fn round_trip(&self) -> u64 {
+        let total = self.client_receive_time.saturating_sub(self.client_send_time);
+        let server_processing = self.server_send_time.saturating_sub(self.server_receive_time);
+        total.saturating_sub(server_processing)
+    }
+
+    /// How far ahead of the client's clock the server's clock is, in nanoseconds.
+    pub fn offset(&self) -> i64 {
+        let outbound = self.server_receive_time as i64 - self.client_send_time as i64;
+        let inbound = self.server_send_time as i64 - self.client_receive_time as i64;
+        (outbound + inbound) / 2
+    }
+}
+
+/// A local clock paired with its estimated offset from the server, for converting timestamps on
+/// the server's timeline into local instants.
+#[derive(Copy, Clone, Debug)]
+pub struct SyncedClock {
+    local: Clock,
+    offset: i64,
+}
+
+impl SyncedClock {
+    /// Builds a synced clock from the exchange with the smallest round trip, as that one gives the
+    /// least room for asymmetric network delays to skew the offset.
+    pub fn from_samples(local: Clock, samples: &[ClockSample]) -> Option<Self> {
+        samples.iter()
+            .min_by_key(|sample| sample.round_trip())
+            .map(|sample| Self {
+                local,
+                offset: sample.offset(),
+            })
+    }
+
+    pub fn offset(&self) -> i64 {
+        self.offset
+    }
+
+    pub fn local_instant(&self, server_time: u64) -> Instant {
+        let local_time = server_time as i64 - self.offset;
+        self.local.instant(if local_time > 0 { local_time as u64 } else { 0 })
+    }
+}
@@ -1,12 +1,25 @@
-use bincode::{serialize_into, deserialize_from};
+use bincode::{serialize, deserialize};
 use bincode;
 
-use std::net::TcpStream;
+use std::sync::mpsc::{sync_channel, SyncSender, Receiver, TrySendError};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::net::{TcpStream, Shutdown};
+use std::thread::{spawn, JoinHandle};
+use std::sync::{Arc, Mutex};
+use std::io::{Read, Write};
+use std::time::Duration;
+use std::error::Error;
 use std::mem::replace;
+use std::fmt;
+use std::io;
 
-use super::packet::Packet;
+use super::packet::{Packet, Capabilities};
+use super::recording::Recorder;
 
-#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+/// Bounds how long a send to a stalled client can block before it's treated as a failure.
+const WRITE_TIMEOUT: Duration = Duration::from_secs(2);
+
+#[derive(Serialize, Deserialize, Copy, Clone, Debug, Eq, PartialEq, Ord, PartialOrd, Hash)]
 pub struct ClientUID(usize);
 
 impl ClientUID {
@@ -35,12 +48,18 @@ impl ClientUIDFactory {
 #[derive(Clone, Debug)]
 pub struct ClientInfo {
     pub uid: ClientUID,
+    pub protocol_version: u32,
+    pub name: String,
+    pub capabilities: Capabilities,
 }
 
 impl ClientInfo {
-    pub fn new(uid: ClientUID) -> Self {
+    pub fn new(uid: ClientUID, protocol_version: u32, name: String, capabilities: Capabilities) -> Self {
         Self {
             uid,
+            protocol_version,
+            name,
+            capabilities,
         }
     }
 }
@@ -48,26 +67,264 @@ impl ClientInfo {
 pub struct Connection {
     pub info: ClientInfo,
     pub stream: TcpStream,
+    /// Each packet queued along with when it was meant to be sent, if it was sent on a schedule.
+    outbound: SyncSender<(Packet, Option<u64>)>,
+    queued: Arc<AtomicUsize>,
+    failure: Arc<Mutex<Option<String>>>,
+    writer: JoinHandle<()>,
+}
+
+#[derive(Debug)]
+pub enum SendError {
+    /// The client's outbound queue is full because it isn't keeping up with what it's being sent.
+    QueueFull,
+    /// The connection has failed and nothing more can be sent over it.
+    Failed(String),
+}
+
+impl fmt::Display for SendError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            SendError::QueueFull => write!(f, "outbound queue is full"),
+            SendError::Failed(reason) => write!(f, "{}", reason),
+        }
+    }
 }
 
 impl Connection {
-    pub fn new(stream: TcpStream, info: ClientInfo) -> Self {
+    /// Wraps a handshaken client's stream, starting a writer thread that sends everything queued
+    /// with `send` so that one congested client can't hold up sends to any other.
+    pub fn new(stream: TcpStream, info: ClientInfo, codec: Codec, queue_size: usize, recorder: Option<Recorder>) -> Self {
         stream.set_nodelay(true)
             .expect("failed to set connection to be no-delay");
+        stream.set_write_timeout(Some(WRITE_TIMEOUT))
+            .expect("failed to set connection write timeout");
+
+        let (outbound, receiver) = sync_channel(queue_size);
+        let queued = Arc::new(AtomicUsize::new(0));
+        let failure = Arc::new(Mutex::new(None));
+
+        let writer = {
+            let stream = stream.try_clone()
+                .expect("failed to clone client stream for writing");
+            let queued = queued.clone();
+            let failure = failure.clone();
+            let uid = info.uid;
+            spawn(move || write_packets(stream, uid, codec, receiver, queued, failure, recorder))
+        };
 
         Self {
             stream,
             info,
+            outbound,
+            queued,
+            failure,
+            writer,
         }
     }
 }
 
 impl Connection {
-    pub fn send(&self, packet: Packet) -> Result<(), Box<bincode::ErrorKind>> {
-        serialize_into(&self.stream, &packet)
+    pub fn send(&self, packet: Packet) -> Result<(), SendError> {
+        self.enqueue(packet, None)
     }
 
-    pub fn recv(&self) -> Result<Packet, Box<bincode::ErrorKind>> {
-        deserialize_from(&self.stream)
+    /// Sends a packet that was meant to go at the given time on the server's clock, so that any
+    /// recording can tell how far from schedule it went.
+    pub fn send_scheduled(&self, packet: Packet, scheduled: u64) -> Result<(), SendError> {
+        self.enqueue(packet, Some(scheduled))
+    }
+
+    fn enqueue(&self, packet: Packet, scheduled: Option<u64>) -> Result<(), SendError> {
+        if let Some(reason) = self.failure() {
+            return Err(SendError::Failed(reason));
+        }
+
+        self.queued.fetch_add(1, Ordering::SeqCst);
+        match self.outbound.try_send((packet, scheduled)) {
+            Ok(()) => Ok(()),
+            Err(e) => {
+                self.queued.fetch_sub(1, Ordering::SeqCst);
+                match e {
+                    TrySendError::Full(_) => Err(SendError::QueueFull),
+                    TrySendError::Disconnected(_) => Err(SendError::Failed(
+                        self.failure().unwrap_or_else(|| "writer stopped".into())
+                    )),
+                }
+            },
+        }
+    }
+
+    /// The number of packets waiting to be written to the client.
+    pub fn queued(&self) -> usize {
+        self.queued.load(Ordering::SeqCst)
+    }
+
+    /// Waits for everything already queued to be written, then shuts the connection down.
+    pub fn close(self) {
+        let Connection { stream, outbound, writer, .. } = self;
+
+        drop(outbound);
+        writer.join().ok();
+        stream.shutdown(Shutdown::Both).ok();
+    }
+
+    fn failure(&self) -> Option<String> {
+        self.failure.lock()
+            .expect("failed to acquire connection failure mutex")
+            .clone()
+    }
+}
+
+fn write_packets(stream: TcpStream, uid: ClientUID, codec: Codec, receiver: Receiver<(Packet, Option<u64>)>, queued: Arc<AtomicUsize>, failure: Arc<Mutex<Option<String>>>, recorder: Option<Recorder>) {
+    for (packet, scheduled) in receiver.iter() {
+        let result = codec.send(&stream, &packet);
+        queued.fetch_sub(1, Ordering::SeqCst);
+
+        if let Err(e) = result {
+            *failure.lock().expect("failed to acquire connection failure mutex") = Some(e.to_string());
+            break;
+        }
+
+        if let Some(ref recorder) = recorder {
+            recorder.record(vec![uid], scheduled, &packet);
+        }
+    }
+}
+
+/// Frames packets for the wire: a big-endian `u32` payload length, a flags byte, a big-endian
+/// CRC-32 of the payload if the checksum flag is set, then the bincode encoded packet itself.
+#[derive(Copy, Clone, Debug)]
+pub struct Codec {
+    checksums: bool,
+}
+
+const FLAG_CHECKSUM: u8 = 0x01;
+
+/// Frames larger than this are refused, as a peer sending one is either broken or hostile.
+pub const MAX_FRAME_SIZE: usize = 256 * 1024;
+
+#[derive(Debug)]
+pub enum FrameError {
+    Io(io::Error),
+    FrameTooLarge {
+        length: usize,
+    },
+    ChecksumMismatch {
+        expected: u32,
+        actual: u32,
+    },
+    Malformed(Box<bincode::ErrorKind>),
+}
+
+impl fmt::Display for FrameError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            FrameError::Io(e) => write!(f, "{}", e),
+            FrameError::FrameTooLarge { length } => write!(f, "frame of {} bytes exceeds limit of {} bytes", length, MAX_FRAME_SIZE),
+            FrameError::ChecksumMismatch { expected, actual } => write!(f, "frame checksum {:08x} doesn't match payload checksum {:08x}", expected, actual),
+            FrameError::Malformed(e) => write!(f, "malformed packet: {}", e),
+        }
+    }
+}
+
+impl Error for FrameError {
+    fn description(&self) -> &str {
+        match self {
+            FrameError::Io(_) => "I/O error",
+            FrameError::FrameTooLarge { .. } => "frame too large",
+            FrameError::ChecksumMismatch { .. } => "frame checksum mismatch",
+            FrameError::Malformed(_) => "malformed packet",
+        }
+    }
+}
+
+impl From<io::Error> for FrameError {
+    fn from(e: io::Error) -> Self {
+        FrameError::Io(e)
+    }
+}
+
+impl Codec {
+    /// Checksums are only added to sent frames if enabled, received frames are verified whenever
+    /// the sender included one.
+    pub fn new(checksums: bool) -> Self {
+        Self {
+            checksums,
+        }
+    }
+
+    pub fn send<W: Write>(&self, mut writer: W, packet: &Packet) -> Result<(), FrameError> {
+        let payload = serialize(packet)
+            .map_err(FrameError::Malformed)?;
+        if payload.len() > MAX_FRAME_SIZE {
+            return Err(FrameError::FrameTooLarge { length: payload.len() });
+        }
+
+        // assembled up front so the frame goes out in as few writes as possible
+        let mut frame = Vec::with_capacity(payload.len() + 9);
+        frame.extend_from_slice(&u32_to_bytes(payload.len() as u32));
+        if self.checksums {
+            frame.push(FLAG_CHECKSUM);
+            frame.extend_from_slice(&u32_to_bytes(crc32(&payload)));
+        } else {
+            frame.push(0);
+        }
+        frame.extend_from_slice(&payload);
+
+        writer.write_all(&frame)?;
+        Ok(())
+    }
+
+    pub fn recv<R: Read>(&self, mut reader: R) -> Result<Packet, FrameError> {
+        let mut header = [0u8; 5];
+        reader.read_exact(&mut header)?;
+
+        let length = bytes_to_u32(&header[0..4]) as usize;
+        if length > MAX_FRAME_SIZE {
+            return Err(FrameError::FrameTooLarge { length });
+        }
+
+        let expected_checksum = if header[4] & FLAG_CHECKSUM != 0 {
+            let mut checksum = [0u8; 4];
+            reader.read_exact(&mut checksum)?;
+            Some(bytes_to_u32(&checksum))
+        } else {
+            None
+        };
+
+        let mut payload = vec![0u8; length];
+        reader.read_exact(&mut payload)?;
+
+        if let Some(expected) = expected_checksum {
+            let actual = crc32(&payload);
+            if actual != expected {
+                return Err(FrameError::ChecksumMismatch { expected, actual });
+            }
+        }
+
+        deserialize(&payload)
+            .map_err(FrameError::Malformed)
+    }
+}
+
+fn u32_to_bytes(value: u32) -> [u8; 4] {
+    [(value >> 24) as u8, (value >> 16) as u8, (value >> 8) as u8, value as u8]
+}
+
+fn bytes_to_u32(bytes: &[u8]) -> u32 {
+    ((bytes[0] as u32) << 24) | ((bytes[1] as u32) << 16) | ((bytes[2] as u32) << 8) | (bytes[3] as u32)
+}
+
+/// The CRC-32 used by zip and ethernet, computed bitwise as frames are small.
+fn crc32(data: &[u8]) -> u32 {
+    let mut crc = 0xFFFF_FFFFu32;
+    for byte in data {
+        crc ^= *byte as u32;
+        for _ in 0..8 {
+            let mask = (crc & 1).wrapping_neg();
+            crc = (crc >> 1) ^ (0xEDB8_8320 & mask);
+        }
     }
+    !crc
 }
@@ -0,0 +1,118 @@
+use midi::Position;
+
+use std::io::{BufRead, BufReader, Write};
+use std::sync::mpsc::Sender;
+use std::net::{TcpListener, TcpStream};
+use std::str::FromStr;
+use std::thread::spawn;
+use std::io;
+
+/// Instructions for the playback loop, typed on stdin or sent to the control socket one per line.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Command {
+    /// Ends the wait for clients to connect, see `--wait-for-enter`.
+    Start,
+    Pause,
+    Resume,
+    Seek(Position),
+    /// Moves on to the next song in the playlist.
+    Skip,
+    Stop,
+}
+
+impl FromStr for Command {
+    type Err = String;
+
+    fn from_str(text: &str) -> Result<Self, Self::Err> {
+        let mut words = text.split_whitespace();
+
+        let command = match (words.next(), words.next()) {
+            (Some("start"), None) => Command::Start,
+            (Some("pause"), None) => Command::Pause,
+            (Some("resume"), None) => Command::Resume,
+            (Some("seek"), Some(position)) => Command::Seek(position.parse()?),
+            (Some("skip"), None) => Command::Skip,
+            (Some("stop"), None) => Command::Stop,
+            _ => return Err(format!("unknown command '{}', expected start, pause, resume, seek <seconds|bar:beat>, skip or stop", text.trim())),
+        };
+
+        if words.next().is_some() {
+            return Err(format!("unexpected arguments in '{}'", text.trim()));
+        }
+
+        Ok(command)
+    }
+}
+
+/// Reads commands typed on stdin until it closes.
+pub fn listen_stdin(commands: Sender<Command>) {
+    spawn(move || {
+        let stdin = io::stdin();
+        for line in stdin.lock().lines() {
+            let line = match line {
+                Ok(line) => line,
+                Err(_) => break,
+            };
+
+            // pressing enter on its own is the quickest way to get things going
+            let parsed = if line.trim().is_empty() {
+                Ok(Command::Start)
+            } else {
+                line.parse()
+            };
+
+            match parsed {
+                Ok(command) => {
+                    if commands.send(command).is_err() {
+                        break;
+                    }
+                },
+                Err(e) => println!("{}", e),
+            }
+        }
+    });
+}
+
+/// Accepts commands from local connections on the given port, answering each line with `ok` or
+/// an error.
+pub fn listen_socket(port: u16, commands: Sender<Command>) -> io::Result<()> {
+    // only reachable from this machine, anyone who can connect can stop the show
+    let listener = TcpListener::bind(("127.0.0.1", port))?;
+
+    spawn(move || {
+        for stream in listener.incoming() {
+            if let Ok(stream) = stream {
+                let commands = commands.clone();
+                spawn(move || serve_control_connection(stream, commands));
+            }
+        }
+    });
+
+    Ok(())
+}
+
+fn serve_control_connection(stream: TcpStream, commands: Sender<Command>) {
+    let mut writer = match stream.try_clone() {
+        Ok(writer) => writer,
+        Err(_) => return,
+    };
+
+    for line in BufReader::new(stream).lines() {
+        let line = match line {
+            Ok(line) => line,
+            Err(_) => break,
+        };
+
+        let reply = match line.parse() {
+            Ok(command) => match commands.send(command) {
+                Ok(_) => "ok".to_string(),
+                Err(_) => "error: playback has finished".to_string(),
+            },
+            Err(e) => format!("error: {}", e),
+        };
+
+        if writeln!(writer, "{}", reply).is_err() {
+            break;
+        }
+    }
+}
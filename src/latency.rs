@@ -0,0 +1,33 @@
+use std::time::Duration;
+
+use convert_duration::*;
+
+/// A rolling estimate of a client's round trip time, smoothed the same way TCP smooths its own so
+/// that one slow ping doesn't throw off the send times of every following note.
+#[derive(Copy, Clone, Debug)]
+pub struct RoundTripEstimate {
+    smoothed: Option<u64>,
+}
+
+impl RoundTripEstimate {
+    pub fn new() -> Self {
+        Self {
+            smoothed: None,
+        }
+    }
+
+    pub fn record(&mut self, round_trip: u64) {
+        self.smoothed = Some(match self.smoothed {
+            Some(smoothed) => smoothed - (smoothed / 8) + (round_trip / 8),
+            None => round_trip,
+        });
+    }
+
+    pub fn round_trip(&self) -> Duration {
+        nanoseconds_to_duration(self.smoothed.unwrap_or(0))
+    }
+
+    pub fn one_way(&self) -> Duration {
+        self.round_trip() / 2
+    }
+}
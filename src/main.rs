@@ -11,13 +11,27 @@ extern crate synth;
 extern crate rodio;
 extern crate clap;
 extern crate pbr;
+extern crate net2;
+extern crate rand;
+extern crate serde_json;
 
 mod convert_duration;
 mod connection;
+mod multicast;
+mod control;
+mod playlist;
+mod status;
+mod clock;
+mod schedule;
+mod latency;
 mod policies;
 mod packet;
 mod server;
 mod client;
+mod simulate;
+mod recording;
+mod rendering;
+mod player;
 mod beep;
 mod midi;
 
@@ -25,6 +39,9 @@ use clap::{Arg, App, AppSettings, SubCommand};
 
 use server::server;
 use client::client;
+use simulate::simulate;
+use recording::summarise;
+use rendering::bake;
 
 fn main() {
     let matches = App::new("midi-orchestra-rs")
@@ -36,65 +53,171 @@ fn main() {
         .subcommand(SubCommand::with_name("server")
             .about("reads MIDI files and orchestrates clients to play it")
             .arg(Arg::with_name("midi")
-                .required(true)
-                .help("path to the midi file to play"))
+                .required_unless("replay")
+                .multiple(true)
+                .help("midi files to play, or directories of them, or m3u playlists listing them"))
+            .arg(Arg::with_name("replay")
+                .long("replay")
+                .value_name("FILE")
+                .conflicts_with("midi")
+                .help("plays a rendering made with bake instead, as it was rendered"))
+            .arg(Arg::with_name("shuffle")
+                .long("shuffle")
+                .help("plays the songs in a random order"))
+            .arg(Arg::with_name("repeat")
+                .long("repeat")
+                .help("starts the playlist again once every song has been played"))
             .arg(Arg::with_name("port")
                 .short("p")
                 .long("port")
                 .default_value("4000")
                 .help("port to listen for client connections on"))
-            .arg(Arg::with_name("policy")
-                .long("policy")
-                .default_value("by-freq")
-                .help("determines policy used to assign a note to a particular client")
+            .arg(Arg::with_name("lead")
+                .long("lead")
+                .value_name("MILLISECONDS")
+                .default_value("100")
+                .help("how far ahead of playback notes are sent, giving clients time to schedule them"))
+            .arg(Arg::with_name("queue size")
+                .long("queue-size")
+                .value_name("PACKETS")
+                .default_value("256")
+                .help("how many packets may wait to be sent to each client before it's considered to be lagging"))
+            .arg(Arg::with_name("overflow")
+                .long("overflow")
+                .default_value("drop")
+                .help("what to do with a lagging client's notes")
                 .possible_values(&[
-                    "broadcast",
-                    "by-track",
-                    "by-channel",
-                    "by-freq",
-                    "by-freq-spreadX2",
+                    "drop",
+                    "reassign",
                 ]))
+            .arg(Arg::with_name("stream notes")
+                .long("stream-notes")
+                .help("sends separate note on/off packets rather than notes with their duration up front"))
+            .arg(Arg::with_name("lookahead")
+                .long("lookahead")
+                .value_name("MILLISECONDS")
+                .conflicts_with("stream notes")
+                .help("batches each client's notes into windows of this length, sending one packet per window"))
+            .arg(Arg::with_name("checksums")
+                .long("checksums")
+                .help("adds a checksum to every packet sent so that corruption is detected"))
+            .arg(Arg::with_name("multicast")
+                .long("multicast")
+                .value_name("GROUP:PORT")
+                .help("multicasts notes to clients on this group rather than sending to each over TCP, e.g. 239.255.77.77:4001"))
+            .arg(Arg::with_name("control port")
+                .long("control-port")
+                .value_name("PORT")
+                .help("also accepts start, pause, resume, seek, skip and stop commands from local connections on this port"))
+            .arg(Arg::with_name("record")
+                .long("record")
+                .value_name("FILE")
+                .help("appends every packet sent, with when and who to, to this file as JSON lines"))
+            .arg(Arg::with_name("status port")
+                .long("status-port")
+                .value_name("PORT")
+                .help("serves a JSON status report at http://127.0.0.1:PORT/status"))
+            .arg(Arg::with_name("wait for clients")
+                .long("wait-for-clients")
+                .value_name("COUNT")
+                .help("waits until at least this many clients have connected before playing"))
+            .arg(Arg::with_name("wait for names")
+                .long("wait-for-names")
+                .value_name("NAME")
+                .multiple(true)
+                .use_delimiter(true)
+                .help("waits until clients with each of these names have connected before playing"))
+            .arg(Arg::with_name("wait for enter")
+                .long("wait-for-enter")
+                .help("waits for enter to be pressed (or a start command) before playing"))
+            .arg(Arg::with_name("wait timeout")
+                .long("wait-timeout")
+                .value_name("SECONDS")
+                .help("starts playing after this long even if still waiting, defaults to 5 seconds when not waiting for anything else"))
             .arg(Arg::with_name("volume")
                 .long("volume")
                 .default_value("1.0")
                 .help("coefficient to multiply note volumes by"))
-            .arg(Arg::with_name("verbose")
-                .short("v")
-                .long("verbose")
-                .help("enable verbose output"))
-            .arg(Arg::with_name("exclude track")
-                .long("exclude-track")
-                .value_name("TRACK")
-                .multiple(true)
-                .conflicts_with("include track")
-                .help("marks a track number for exclusion from playback"))
-            .arg(Arg::with_name("include track")
-                .long("include-track")
-                .value_name("TRACK")
-                .multiple(true)
-                .conflicts_with("exclude track")
-                .help("marks a track number for inclusion in playback"))
-            .arg(Arg::with_name("exclude channel")
-                .long("exclude-channel")
-                .value_name("CHANNEL")
-                .multiple(true)
-                .conflicts_with("include channel")
-                .help("marks a channel number for exclusion from playback"))
-            .arg(Arg::with_name("include channel")
-                .long("include-channel")
-                .value_name("CHANNEL")
-                .multiple(true)
-                .conflicts_with("exclude channel")
-                .help("marks a channel number for inclusion in playback"))
-            .arg(Arg::with_name("allow channel 10")
-                .long("--allow-channel-10")
-                .help("channel 10 is ignored as percussion, this flag allows channel 10 to play")))
+            .arg(Arg::with_name("loop")
+                .long("loop")
+                .help("repeats each song, or the part of it between --start-at and --end-at, until skipped or stopped"))
+            .args(&song_args()))
+
+        .subcommand(SubCommand::with_name("simulate")
+            .about("runs a MIDI file through a policy with pretend clients, reporting what each would play")
+            .arg(Arg::with_name("midi")
+                .required(true)
+                .help("path to the midi file to simulate"))
+            .arg(Arg::with_name("clients")
+                .short("c")
+                .long("clients")
+                .value_name("COUNT")
+                .default_value("4")
+                .help("how many clients to pretend are connected"))
+            .arg(Arg::with_name("max polyphony")
+                .long("max-polyphony")
+                .value_name("NOTES")
+                .default_value("16")
+                .help("warns about clients asked to play more notes than this at once"))
+            .args(&song_args()))
+
+        .subcommand(SubCommand::with_name("bake")
+            .about("renders a MIDI file through a policy into a file of the packets each client slot is sent, for server --replay")
+            .arg(Arg::with_name("midi")
+                .required(true)
+                .help("path to the midi file to bake"))
+            .arg(Arg::with_name("output")
+                .short("o")
+                .long("output")
+                .value_name("FILE")
+                .required(true)
+                .help("where to write the rendering, as JSON lines"))
+            .arg(Arg::with_name("clients")
+                .short("c")
+                .long("clients")
+                .value_name("COUNT")
+                .default_value("4")
+                .help("how many client slots to share the music between"))
+            .arg(Arg::with_name("volume")
+                .long("volume")
+                .default_value("1.0")
+                .help("coefficient to multiply note volumes by"))
+            .args(&song_args()))
+
+        .subcommand(SubCommand::with_name("summarise")
+            .about("summarises a recording made with server --record, e.g. how late packets were sent to each client")
+            .arg(Arg::with_name("recording")
+                .required(true)
+                .help("path to the recording to summarise")))
 
         .subcommand(SubCommand::with_name("client")
             .about("connects to a server and dutifully plays note on command")
             .arg(Arg::with_name("target")
                 .required(true)
                 .help("hostname and port combination of the server to connect to"))
+            .arg(Arg::with_name("name")
+                .short("n")
+                .long("name")
+                .default_value("anonymous")
+                .help("human-readable name reported to the server"))
+            .arg(Arg::with_name("max polyphony")
+                .long("max-polyphony")
+                .value_name("NOTES")
+                .default_value("16")
+                .help("maximum number of notes this client will play at once"))
+            .arg(Arg::with_name("lowest frequency")
+                .long("lowest-frequency")
+                .value_name("HZ")
+                .default_value("20.0")
+                .help("lowest frequency this client can usefully play"))
+            .arg(Arg::with_name("highest frequency")
+                .long("highest-frequency")
+                .value_name("HZ")
+                .default_value("20000.0")
+                .help("highest frequency this client can usefully play"))
+            .arg(Arg::with_name("checksums")
+                .long("checksums")
+                .help("adds a checksum to every packet sent so that corruption is detected"))
             .arg(Arg::with_name("forever")
                 .short("f")
                 .long("forever")
@@ -105,6 +228,77 @@ fn main() {
     match matches.subcommand() {
         ("server", Some(matches)) => server(matches),
         ("client", Some(matches)) => client(matches),
+        ("simulate", Some(matches)) => simulate(matches),
+        ("bake", Some(matches)) => bake(matches),
+        ("summarise", Some(matches)) => summarise(matches),
         (command, _) => panic!("unknown command: {}", command),
     }
 }
+
+/// Arguments that choose which parts of a song are played, and how, shared by every subcommand
+/// that loads songs.
+fn song_args<'a, 'b>() -> Vec<Arg<'a, 'b>> {
+    vec![
+        Arg::with_name("policy")
+            .long("policy")
+            .default_value("by-freq")
+            .help("determines policy used to assign a note to a particular client")
+            .possible_values(&[
+                "broadcast",
+                "by-track",
+                "by-channel",
+                "by-freq",
+                "by-freq-spreadX2",
+            ]),
+        Arg::with_name("verbose")
+            .short("v")
+            .long("verbose")
+            .help("enable verbose output"),
+        Arg::with_name("tempo")
+            .long("tempo")
+            .value_name("FACTOR")
+            .default_value("1.0")
+            .help("multiplies the speed of playback, e.g. 0.5 for half speed"),
+        Arg::with_name("transpose")
+            .long("transpose")
+            .value_name("SEMITONES")
+            .default_value("0")
+            .allow_hyphen_values(true)
+            .help("shifts every note up (or down, if negative) by this many semitones"),
+        Arg::with_name("start at")
+            .long("start-at")
+            .value_name("POSITION")
+            .help("starts each song from this many seconds in, or from a bar:beat such as 12:1"),
+        Arg::with_name("end at")
+            .long("end-at")
+            .value_name("POSITION")
+            .help("ends each song this many seconds in, or at a bar:beat such as 20:1"),
+        Arg::with_name("exclude track")
+            .long("exclude-track")
+            .value_name("TRACK")
+            .multiple(true)
+            .conflicts_with("include track")
+            .help("marks a track, by number or by name, for exclusion from playback"),
+        Arg::with_name("include track")
+            .long("include-track")
+            .value_name("TRACK")
+            .multiple(true)
+            .conflicts_with("exclude track")
+            .help("marks a track, by number or by name, for inclusion in playback"),
+        Arg::with_name("exclude channel")
+            .long("exclude-channel")
+            .value_name("CHANNEL")
+            .multiple(true)
+            .conflicts_with("include channel")
+            .help("marks a channel number for exclusion from playback"),
+        Arg::with_name("include channel")
+            .long("include-channel")
+            .value_name("CHANNEL")
+            .multiple(true)
+            .conflicts_with("exclude channel")
+            .help("marks a channel number for inclusion in playback"),
+        Arg::with_name("allow channel 10")
+            .long("--allow-channel-10")
+            .help("channel 10 is ignored as percussion, this flag allows channel 10 to play"),
+    ]
+}
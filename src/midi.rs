@@ -1,12 +1,19 @@
-use std::collections::HashMap;
+use std::collections::{HashMap, BTreeMap};
 use std::time::Duration;
+use std::str::FromStr;
+use std::cmp::{min, max};
 use std::path::Path;
+use std::io::Read;
+use std::fs::File;
+use std::error::Error;
+use std::fmt;
+use std::io;
 
 use priority_queue::PriorityQueue;
-use ghakuf::{messages, messages::{MetaEvent, SysExEvent}, reader::Reader};
+use ghakuf::{messages, messages::{MetaEvent, SysExEvent}, reader::{Reader, ReadError}};
 use ghakuf;
 
-use convert_duration::seconds_to_duration;
+use convert_duration::{seconds_to_duration, duration_to_seconds};
 
 #[derive(Clone, Debug)]
 pub struct Timing {
@@ -25,28 +32,146 @@ pub fn clocks_to_duration(timing: &Timing, clocks: Ticks) -> Duration {
 
 pub struct Music {
     events: Vec<MusicalEvent>,
+    metadata: Metadata,
 }
 
 impl Music {
     pub fn events(&self) -> &[MusicalEvent] {
         &self.events
     }
+
+    pub fn metadata(&self) -> &Metadata {
+        &self.metadata
+    }
 }
 
-pub fn load_midi<P: AsRef<Path>>(path: P, verbose: bool) -> Music {
-    let mut handler = Handler::new(verbose);
+/// What a file says about the music, besides the music itself.
+#[derive(Clone, Debug, Default)]
+pub struct Metadata {
+    /// The name of the first track, which names the whole piece.
+    pub title: Option<String>,
+    pub track_names: BTreeMap<usize, String>,
+    pub copyright: Option<String>,
+    /// Any other text, such as comments or credits.
+    pub text: Vec<String>,
+    pub key_signatures: Vec<KeySignature>,
+    pub markers: Vec<Marker>,
+}
+
+impl Metadata {
+    /// Finds every track with the given name, ignoring case.
+    pub fn tracks_named(&self, name: &str) -> Vec<usize> {
+        self.track_names.iter()
+            .filter(|&(_, track_name)| track_name.trim().eq_ignore_ascii_case(name.trim()))
+            .map(|(track, _)| *track)
+            .collect()
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct KeySignature {
+    pub start_offset: Duration,
+    /// Negative for flats.
+    pub sharps: i8,
+    pub minor: bool,
+}
+
+impl KeySignature {
+    /// The key's name, e.g. `Bb major`.
+    pub fn name(&self) -> String {
+        const MAJOR: [&str; 15] = ["Cb", "Gb", "Db", "Ab", "Eb", "Bb", "F", "C", "G", "D", "A", "E", "B", "F#", "C#"];
+        const MINOR: [&str; 15] = ["Ab", "Eb", "Bb", "F", "C", "G", "D", "A", "E", "B", "F#", "C#", "G#", "D#", "A#"];
+
+        let (names, mode) = if self.minor { (MINOR, "minor") } else { (MAJOR, "major") };
+        match names.get((self.sharps as i32 + 7) as usize) {
+            Some(name) => format!("{} {}", name, mode),
+            None => format!("{} sharps {}", self.sharps, mode),
+        }
+    }
+}
+
+/// A named point in the music, such as the start of a verse.
+#[derive(Clone, Debug)]
+pub struct Marker {
+    pub start_offset: Duration,
+    pub text: String,
+}
 
-    {
-        let mut midi_reader = Reader::new(
-            &mut handler,
-            path.as_ref(),
-        ).unwrap();
+#[derive(Debug)]
+pub enum MidiLoadError {
+    Io(io::Error),
+    /// The file doesn't start with a standard MIDI file header.
+    MalformedHeader(String),
+    /// The file ends, or stops making sense, part way through the given track (counting from 1).
+    TruncatedTrack {
+        track: usize,
+        reason: String,
+    },
+    /// The file is valid, but not something that can be played.
+    UnsupportedFormat(String),
+}
 
-        let _ = midi_reader.read();
+impl fmt::Display for MidiLoadError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            MidiLoadError::Io(e) => write!(f, "{}", e),
+            MidiLoadError::MalformedHeader(reason) => write!(f, "malformed header: {}", reason),
+            MidiLoadError::TruncatedTrack { track, reason } => write!(f, "track {} is truncated: {}", track, reason),
+            MidiLoadError::UnsupportedFormat(reason) => write!(f, "unsupported format: {}", reason),
+        }
+    }
+}
+
+impl Error for MidiLoadError {
+    fn description(&self) -> &str {
+        match self {
+            MidiLoadError::Io(_) => "I/O error",
+            MidiLoadError::MalformedHeader(_) => "malformed header",
+            MidiLoadError::TruncatedTrack { .. } => "truncated track",
+            MidiLoadError::UnsupportedFormat(_) => "unsupported format",
+        }
+    }
+}
+
+/// What went wrong reading a file, before it's known whether it was in the header or a track.
+enum ReadFailure {
+    Io(io::Error),
+    Invalid(String),
+}
+
+impl<'a> From<ReadError<'a>> for ReadFailure {
+    fn from(e: ReadError<'a>) -> Self {
+        match e {
+            ReadError::Io(e) => ReadFailure::Io(e),
+            e => ReadFailure::Invalid(e.to_string()),
+        }
+    }
+}
+
+pub fn load_midi<P: AsRef<Path>>(path: P, verbose: bool) -> Result<Music, MidiLoadError> {
+    let mut handler = Handler::new(verbose);
+    let scanned_bends = scan_pitch_bends(path.as_ref());
+    if let Ok(ref bends) = scanned_bends {
+        handler.pitch_bends = bends.clone();
+    }
+
+    let read = {
+        // the reader holds on to the handler, so failures are only explained once it's gone
+        Reader::new(&mut handler, path.as_ref())
+            .map_err(ReadFailure::from)
+            .and_then(|mut midi_reader| midi_reader.read().map_err(ReadFailure::from))
+    };
+    if let Err(failure) = read {
+        return Err(handler.explain(failure));
+    }
+    handler.check_complete()?;
+    // a file the reader couldn't make sense of is better explained by it than by the scan
+    if let Err(e) = scanned_bends {
+        println!("warning: unable to read pitch bends, playing without them: {}", e);
     }
 
     let division = handler.get_division();
-    let midi = handler.into_music();
+    let (midi, mut metadata) = handler.into_music();
 
     let mut last_start_tick = Ticks(0);
     let mut offset_of_last_event = Duration::new(0, 0);
@@ -57,13 +182,21 @@ pub fn load_midi<P: AsRef<Path>>(path: P, verbose: bool) -> Music {
         time_signature_denominator: 4.0,
     };
 
-    let mut events = Vec::new();
+    // the timing in force before any tempo or time signature changes, so that every piece starts
+    // with a known timing
+    let mut events = vec![MusicalEvent::TimingChange(TimingChange {
+        start_offset: Duration::new(0, 0),
+        timing: timing.clone(),
+    })];
 
     for event in midi {
         let start_tick = match event {
             MidiEvent::PlayNote { start, .. } => start,
             MidiEvent::ChangeTempo { start, .. } => start,
             MidiEvent::ChangeTimeSignature { start, .. } => start,
+            MidiEvent::PitchBend { start, .. } => start,
+            MidiEvent::KeySignature { start, .. } => start,
+            MidiEvent::Marker { start, .. } => start,
         };
 
         let delta_ticks = Ticks(start_tick.0 - last_start_tick.0);
@@ -74,7 +207,7 @@ pub fn load_midi<P: AsRef<Path>>(path: P, verbose: bool) -> Music {
         offset_of_last_event = start_offset;
 
         match event {
-            MidiEvent::PlayNote { track, channel, note, duration, velocity, .. } => {
+            MidiEvent::PlayNote { track, channel, note, duration, velocity, program, .. } => {
                 let duration = clocks_to_duration(&timing, duration);
                 events.push(MusicalEvent::PlayNote(Note {
                     start_offset,
@@ -83,6 +216,8 @@ pub fn load_midi<P: AsRef<Path>>(path: P, verbose: bool) -> Music {
                     note,
                     duration,
                     velocity,
+                    program,
+                    instrument: InstrumentFamily::from_program(program),
                 }));
             },
             MidiEvent::ChangeTempo { new_tempo, .. } => {
@@ -92,6 +227,28 @@ pub fn load_midi<P: AsRef<Path>>(path: P, verbose: bool) -> Music {
                     timing: timing.clone(),
                 }));
             },
+            MidiEvent::PitchBend { track, channel, value, range_cents, .. } => {
+                events.push(MusicalEvent::PitchBend(PitchBend {
+                    start_offset,
+                    track,
+                    channel,
+                    value,
+                    range: range_cents as f32 / 100.0,
+                }));
+            },
+            MidiEvent::KeySignature { sharps, minor, .. } => {
+                metadata.key_signatures.push(KeySignature {
+                    start_offset,
+                    sharps,
+                    minor,
+                });
+            },
+            MidiEvent::Marker { text, .. } => {
+                metadata.markers.push(Marker {
+                    start_offset,
+                    text,
+                });
+            },
             MidiEvent::ChangeTimeSignature { numerator, denominator_exponent, .. } => {
                 let numerator = numerator as f64;
                 let denominator = 2.0f64.powf(denominator_exponent as f64);
@@ -105,9 +262,10 @@ pub fn load_midi<P: AsRef<Path>>(path: P, verbose: bool) -> Music {
         }
     }
 
-    Music {
+    Ok(Music {
         events,
-    }
+        metadata,
+    })
 }
 
 #[derive(Copy, Clone, Debug, Ord, PartialOrd, Eq, PartialEq, Hash)]
@@ -121,6 +279,10 @@ pub struct Note {
     pub note: u8,
     pub duration: Duration,
     pub velocity: u8,
+    /// The General MIDI program the channel was set to when the note began, counting from 0.
+    /// On channel 10 this picks a drum kit rather than an instrument.
+    pub program: u8,
+    pub instrument: InstrumentFamily,
 }
 
 #[derive(Clone, Debug)]
@@ -129,10 +291,239 @@ pub struct TimingChange {
     pub timing: Timing,
 }
 
+/// Bends every note on a channel, until the next bend on that channel.
+#[derive(Clone, Debug)]
+pub struct PitchBend {
+    pub start_offset: Duration,
+    pub track: usize,
+    pub channel: u8,
+    /// How far the wheel is from the centre, from -8192 to 8191.
+    pub value: i16,
+    /// How many semitones the wheel bends by at either end, as set by RPN 0.
+    pub range: f32,
+}
+
+impl PitchBend {
+    pub fn semitones(&self) -> f32 {
+        self.value as f32 / 8192.0 * self.range
+    }
+}
+
 #[derive(Clone, Debug)]
 pub enum MusicalEvent {
     PlayNote(Note),
     TimingChange(TimingChange),
+    PitchBend(PitchBend),
+}
+
+impl MusicalEvent {
+    pub fn start_offset(&self) -> Duration {
+        match self {
+            MusicalEvent::PlayNote(Note { start_offset, .. }) => *start_offset,
+            MusicalEvent::TimingChange(TimingChange { start_offset, .. }) => *start_offset,
+            MusicalEvent::PitchBend(PitchBend { start_offset, .. }) => *start_offset,
+        }
+    }
+}
+
+/// Plays the music faster (for a factor above one) or slower (below one).
+pub fn scale_tempo(events: &[MusicalEvent], factor: f64) -> Vec<MusicalEvent> {
+    let scale = |duration: Duration| seconds_to_duration(duration_to_seconds(duration) / factor);
+
+    events.iter()
+        .map(|event| match event {
+            MusicalEvent::PlayNote(note) => MusicalEvent::PlayNote(Note {
+                start_offset: scale(note.start_offset),
+                duration: scale(note.duration),
+                ..note.clone()
+            }),
+            MusicalEvent::TimingChange(change) => {
+                let mut timing = change.timing.clone();
+                timing.microseconds_per_quarter_note /= factor;
+
+                MusicalEvent::TimingChange(TimingChange {
+                    start_offset: scale(change.start_offset),
+                    timing,
+                })
+            },
+            MusicalEvent::PitchBend(bend) => MusicalEvent::PitchBend(PitchBend {
+                start_offset: scale(bend.start_offset),
+                ..bend.clone()
+            }),
+        })
+        .collect()
+}
+
+/// Shifts every note by the given number of semitones, clamping them to the range MIDI allows.
+pub fn transpose(events: &[MusicalEvent], semitones: i32) -> Vec<MusicalEvent> {
+    events.iter()
+        .map(|event| match event {
+            MusicalEvent::PlayNote(note) => MusicalEvent::PlayNote(Note {
+                note: max(0, min(127, note.note as i32 + semitones)) as u8,
+                ..note.clone()
+            }),
+            event => event.clone(),
+        })
+        .collect()
+}
+
+/// Keeps only what sounds between `start` and `end`, trimming notes that straddle either rather
+/// than dropping them. Offsets are left as they were, relative to the start of the whole piece.
+pub fn slice(events: &[MusicalEvent], start: Duration, end: Option<Duration>) -> Vec<MusicalEvent> {
+    let before_end = |offset: Duration| end.map(|end| offset < end).unwrap_or(true);
+
+    // each channel starts the slice bent however it was bent by then, by whichever track bent it
+    // last, as bends apply to the whole channel
+    let mut bends_in_force: HashMap<u8, PitchBend> = HashMap::new();
+    for event in events.iter() {
+        if let MusicalEvent::PitchBend(bend) = event {
+            let later = bends_in_force.get(&bend.channel)
+                .map(|in_force| bend.start_offset >= in_force.start_offset)
+                .unwrap_or(true);
+            if bend.start_offset < start && later {
+                bends_in_force.insert(bend.channel, bend.clone());
+            }
+        }
+    }
+
+    let mut sliced = events.iter()
+        .filter_map(|event| match event {
+            MusicalEvent::PlayNote(note) => {
+                let note_end = note.start_offset + note.duration;
+                if before_end(note.start_offset) == false || (note.start_offset < start && note_end <= start) {
+                    return None;
+                }
+
+                let trimmed_start = max(note.start_offset, start);
+                let trimmed_end = end.map(|end| min(note_end, end)).unwrap_or(note_end);
+                Some(MusicalEvent::PlayNote(Note {
+                    start_offset: trimmed_start,
+                    duration: trimmed_end - trimmed_start,
+                    ..note.clone()
+                }))
+            },
+            // earlier timing changes are kept, positions within the slice depend on them
+            MusicalEvent::TimingChange(change) if before_end(change.start_offset) => Some(event.clone()),
+            MusicalEvent::TimingChange(_) => None,
+            MusicalEvent::PitchBend(bend) if bend.start_offset >= start && before_end(bend.start_offset) => Some(event.clone()),
+            MusicalEvent::PitchBend(_) => None,
+        })
+        .chain(bends_in_force.into_iter().map(|(_, bend)| MusicalEvent::PitchBend(PitchBend {
+            start_offset: start,
+            ..bend
+        })))
+        .collect::<Vec<_>>();
+
+    // trimmed notes may now start after events that used to follow them
+    sliced.sort_by_key(|event| event.start_offset());
+    sliced
+}
+
+/// How far every channel that's ever bent is bent at the given offset, in semitones, with those
+/// not bent by then straight.
+pub fn bends_at(events: &[MusicalEvent], offset: Duration) -> BTreeMap<u8, f32> {
+    let mut bends = BTreeMap::new();
+    for event in events.iter() {
+        if let MusicalEvent::PitchBend(bend) = event {
+            if bend.start_offset <= offset {
+                bends.insert(bend.channel, bend.semitones());
+            } else {
+                bends.entry(bend.channel).or_insert(0.0);
+            }
+        }
+    }
+
+    bends
+}
+
+/// Finds when the last note of the music stops sounding.
+pub fn end_offset(events: &[MusicalEvent]) -> Duration {
+    events.iter()
+        .map(|event| match event {
+            MusicalEvent::PlayNote(note) => note.start_offset + note.duration,
+            event => event.start_offset(),
+        })
+        .max()
+        .unwrap_or(Duration::new(0, 0))
+}
+
+/// A point in a piece of music, either as a time from the start or as a (1-indexed) bar and beat.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Position {
+    Time(Duration),
+    Bar {
+        bar: u32,
+        beat: u32,
+    },
+}
+
+impl FromStr for Position {
+    type Err = String;
+
+    /// Parses either seconds, e.g. `42.5`, or a bar and beat, e.g. `12:3`.
+    fn from_str(text: &str) -> Result<Self, Self::Err> {
+        let text = text.trim();
+
+        if let Some(separator) = text.find(':') {
+            let bar = text[..separator].parse::<u32>();
+            let beat = text[separator + 1..].parse::<u32>();
+            match (bar, beat) {
+                (Ok(bar), Ok(beat)) if bar > 0 && beat > 0 => Ok(Position::Bar { bar, beat }),
+                _ => Err(format!("invalid bar:beat '{}', bars and beats count from 1", text)),
+            }
+        } else {
+            match text.parse::<f64>() {
+                Ok(seconds) if seconds >= 0.0 => Ok(Position::Time(seconds_to_duration(seconds))),
+                _ => Err(format!("invalid position '{}', expected seconds or bar:beat", text)),
+            }
+        }
+    }
+}
+
+/// Finds how far into the music the given position is, following any tempo and time signature
+/// changes along the way.
+pub fn position_to_offset(events: &[MusicalEvent], position: Position) -> Duration {
+    let (bar, beat) = match position {
+        Position::Time(offset) => return offset,
+        Position::Bar { bar, beat } => ((bar - 1) as f64, (beat - 1) as f64),
+    };
+
+    let seconds_per_beat = |timing: &Timing| {
+        (timing.microseconds_per_quarter_note / 1_000_000.0) * (4.0 / timing.time_signature_denominator)
+    };
+
+    let mut timing = Timing {
+        ticks_per_quarter_note: 0.0,
+        microseconds_per_quarter_note: 500_000.0,
+        time_signature_numerator: 4.0,
+        time_signature_denominator: 4.0,
+    };
+    let mut segment_start = 0.0;
+    let mut bars_before_segment = 0.0;
+
+    let timing_changes = events.iter()
+        .filter_map(|e| match e {
+            MusicalEvent::TimingChange(change) => Some(change),
+            _ => None,
+        });
+
+    for change in timing_changes {
+        let change_start = duration_to_seconds(change.start_offset);
+        let seconds_per_bar = seconds_per_beat(&timing) * timing.time_signature_numerator;
+        let bars_in_segment = (change_start - segment_start) / seconds_per_bar;
+
+        if bars_before_segment + bars_in_segment > bar {
+            break;
+        }
+
+        segment_start = change_start;
+        bars_before_segment += bars_in_segment;
+        timing = change.timing.clone();
+    }
+
+    let seconds_per_bar = seconds_per_beat(&timing) * timing.time_signature_numerator;
+    let seconds = segment_start + (bar - bars_before_segment) * seconds_per_bar + beat * seconds_per_beat(&timing);
+    seconds_to_duration(seconds.max(0.0))
 }
 
 #[derive(Copy, Clone)]
@@ -141,8 +532,9 @@ struct StartOfNote {
     velocity: u8,
 }
 
-#[derive(Copy, Clone, Debug)]
-enum InstrumentFamily {
+/// The General MIDI groupings of programs, eight programs to each.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+pub enum InstrumentFamily {
     Piano,
     ChromaticPercussion,
     Organ,
@@ -162,7 +554,7 @@ enum InstrumentFamily {
 }
 
 impl InstrumentFamily {
-    fn from_program(program: u8) -> InstrumentFamily {
+    pub fn from_program(program: u8) -> InstrumentFamily {
         match (program >> 3) & 0xF {
             0x0 => InstrumentFamily::Piano,
             0x1 => InstrumentFamily::ChromaticPercussion,
@@ -194,6 +586,7 @@ pub enum MidiEvent {
         start: Ticks,
         duration: Ticks,
         velocity: u8,
+        program: u8,
     },
     ChangeTempo {
         new_tempo: u32,
@@ -204,27 +597,94 @@ pub enum MidiEvent {
         denominator_exponent: u8,
         start: Ticks,
     },
+    PitchBend {
+        track: usize,
+        channel: u8,
+        value: i16,
+        range_cents: u16,
+        start: Ticks,
+    },
+    KeySignature {
+        sharps: i8,
+        minor: bool,
+        start: Ticks,
+    },
+    Marker {
+        text: String,
+        start: Ticks,
+    },
+}
+
+/// The header chunk, as declared at the start of a file.
+#[derive(Copy, Clone, Debug)]
+struct Header {
+    format: u16,
+    tracks: u16,
+    time_base: u16,
 }
 
 pub struct Handler {
     verbose: bool,
     handled: u64,
+    header: Option<Header>,
     division: f64,
     current_time: Ticks,
     current_track: usize,
     book_keeping: HashMap<(u8, u8), StartOfNote>,
+    /// When each channel's sustain and sostenuto pedals went up or down, keyed by channel and
+    /// controller. As with program changes, pedals on one track hold notes on any other track on
+    /// the same channel, so notes are only extended once every track has been read.
+    pedal_changes: HashMap<(u8, u8), Vec<(Ticks, bool)>>,
+    /// How long each track is, which is as long as a pedal that's never let up holds its notes.
+    track_ends: HashMap<usize, Ticks>,
+    /// When each channel changed program, in the order they were read. Tracks are read one after
+    /// another, so notes are only matched up with their program once every track has been read.
+    program_changes: HashMap<u8, Vec<(Ticks, u8)>>,
+    /// Everything but markers and key signatures, which need timing, see `load_midi`.
+    metadata: Metadata,
+    /// The registered parameter each channel's data entry controllers currently change.
+    parameters: HashMap<u8, (u8, u8)>,
+    /// When each channel's pitch bend range was changed, and by which data entry controller to
+    /// what. As with program changes, bends are only matched up with their range once every track
+    /// has been read.
+    bend_range_changes: HashMap<u8, Vec<(Ticks, u8, u8)>>,
+    /// The value of every pitch bend in each track, in order, see `scan_pitch_bends`.
+    pitch_bends: Vec<Vec<i16>>,
+    bends_in_track: usize,
     events: PriorityQueue<MidiEvent, Ticks>,
 }
 
+/// Controllers at or above this value are on, below it off.
+const PEDAL_THRESHOLD: u8 = 64;
+const SUSTAIN_PEDAL: u8 = 64;
+const SOSTENUTO_PEDAL: u8 = 66;
+const DATA_ENTRY: u8 = 6;
+const DATA_ENTRY_FINE: u8 = 38;
+const PARAMETER_FINE: u8 = 100;
+const PARAMETER: u8 = 101;
+/// Registered parameter 0, 0 sets the pitch bend range, in semitones then cents.
+const PITCH_BEND_RANGE: (u8, u8) = (0, 0);
+/// The General MIDI default, two semitones either way.
+const DEFAULT_BEND_RANGE_CENTS: u16 = 200;
+
 impl Handler {
     pub fn new(verbose: bool) -> Self {
         Self {
             verbose,
             handled: 0,
+            header: None,
             division: 0f64,
             current_time: Ticks(0),
             current_track: 0,
             book_keeping: HashMap::new(),
+            pedal_changes: HashMap::new(),
+            track_ends: HashMap::new(),
+            program_changes: HashMap::new(),
+            metadata: Metadata::default(),
+            parameters: HashMap::new(),
+            bend_range_changes: HashMap::new(),
+            pitch_bends: Vec::new(),
+            bends_in_track: 0,
             events: PriorityQueue::new(),
         }
     }
@@ -233,10 +693,117 @@ impl Handler {
         self.division
     }
 
-    pub fn into_music(self) -> Vec<MidiEvent> {
+    /// Works out where in the file a failure to read it happened, from how far reading got.
+    fn explain(&self, failure: ReadFailure) -> MidiLoadError {
+        let in_header = self.header.is_none();
+
+        match failure {
+            // reading got as far as opening the file, at least
+            ReadFailure::Io(ref e) if e.kind() == io::ErrorKind::UnexpectedEof && in_header => {
+                MidiLoadError::MalformedHeader("the file ends before the header does".to_string())
+            },
+            ReadFailure::Io(ref e) if e.kind() == io::ErrorKind::UnexpectedEof => MidiLoadError::TruncatedTrack {
+                track: self.current_track,
+                reason: "the file ends part way through it".to_string(),
+            },
+            ReadFailure::Io(e) => MidiLoadError::Io(e),
+            ReadFailure::Invalid(reason) => {
+                if in_header {
+                    MidiLoadError::MalformedHeader(reason)
+                } else {
+                    MidiLoadError::TruncatedTrack {
+                        track: self.current_track,
+                        reason,
+                    }
+                }
+            },
+        }
+    }
+
+    /// Checks that a file read without error has everything its header promised, in a form that
+    /// can be played.
+    fn check_complete(&self) -> Result<(), MidiLoadError> {
+        let header = match self.header {
+            Some(header) => header,
+            None => return Err(MidiLoadError::MalformedHeader("no header found".to_string())),
+        };
+
+        if header.format > 2 {
+            return Err(MidiLoadError::MalformedHeader(format!("unknown format {}", header.format)));
+        }
+        if header.format == 2 {
+            return Err(MidiLoadError::UnsupportedFormat("format 2 files, of independent sequences, can't be played".to_string()));
+        }
+        if header.time_base & 0x8000 != 0 {
+            return Err(MidiLoadError::UnsupportedFormat("SMPTE time divisions can't be played, only ticks per quarter note".to_string()));
+        }
+        if header.time_base == 0 {
+            return Err(MidiLoadError::MalformedHeader("zero ticks per quarter note".to_string()));
+        }
+        if self.current_track < header.tracks as usize {
+            return Err(MidiLoadError::TruncatedTrack {
+                track: self.current_track + 1,
+                reason: format!("the file ends before it starts, the header promises {} tracks", header.tracks),
+            });
+        }
+
+        Ok(())
+    }
+
+    pub fn into_music(mut self) -> (Vec<MidiEvent>, Metadata) {
+        // the last track is never followed by another, so never changed away from
+        self.track_ends.insert(self.current_track, self.current_time);
+
+        for changes in self.program_changes.values_mut() {
+            // stable, so that changes at the same time within a track keep their order
+            changes.sort_by_key(|&(start, _)| start);
+        }
+        for changes in self.pedal_changes.values_mut() {
+            changes.sort_by_key(|&(start, _)| start);
+        }
+        for changes in self.bend_range_changes.values_mut() {
+            changes.sort_by_key(|&(start, _, _)| start);
+        }
+
         let mut music = self.events.into_sorted_vec();
         music.reverse();
-        music
+
+        // when each key is struck, in order, as striking a key again cuts short a pedalled note
+        let mut strikes: HashMap<(u8, u8), Vec<Ticks>> = HashMap::new();
+        for event in music.iter() {
+            if let MidiEvent::PlayNote { channel, note, start, .. } = *event {
+                strikes.entry((channel, note)).or_insert_with(Vec::new).push(start);
+            }
+        }
+
+        for event in music.iter_mut() {
+            if let MidiEvent::PlayNote { track, channel, note, start, ref mut duration, ref mut program, .. } = *event {
+                // channels start out as the first program, a piano
+                *program = self.program_changes.get(&(channel - 1))
+                    .and_then(|changes| changes.iter().take_while(|&&(at, _)| at <= start).last())
+                    .map(|&(_, program)| program)
+                    .unwrap_or(0);
+
+                let released = Ticks(start.0 + duration.0);
+                let track_end = self.track_ends.get(&track).cloned().unwrap_or(released);
+                let mut end = pedalled_until(&self.pedal_changes, channel - 1, start, released, track_end);
+                let struck_again = strikes.get(&(channel, note))
+                    .and_then(|strikes| strikes.iter().find(|&&strike| strike > start))
+                    .cloned();
+                if let Some(struck_again) = struck_again {
+                    end = max(released, min(end, struck_again));
+                }
+                *duration = Ticks(end.0 - start.0);
+            }
+
+            if let MidiEvent::PitchBend { channel, start, ref mut range_cents, .. } = *event {
+                *range_cents = self.bend_range_changes.get(&(channel - 1))
+                    .map(|changes| bend_range_at(changes, start))
+                    .unwrap_or(DEFAULT_BEND_RANGE_CENTS);
+            }
+        }
+
+        (music, self.metadata)
     }
 
     fn advance_time(&mut self, delta_time: u32) {
@@ -269,6 +836,55 @@ impl Handler {
         });
     }
 
+    fn control_change(&mut self, channel: u8, control: u8, value: u8) {
+        match control {
+            SUSTAIN_PEDAL | SOSTENUTO_PEDAL => {
+                // notes let go of while a pedal is down are extended once every track is read
+                self.pedal_changes.entry((channel, control))
+                    .or_insert_with(Vec::new)
+                    .push((self.current_time, value >= PEDAL_THRESHOLD));
+            },
+            PARAMETER => {
+                let fine = self.parameters.get(&channel).map(|parameter| parameter.1).unwrap_or(127);
+                self.parameters.insert(channel, (value, fine));
+            },
+            PARAMETER_FINE => {
+                let coarse = self.parameters.get(&channel).map(|parameter| parameter.0).unwrap_or(127);
+                self.parameters.insert(channel, (coarse, value));
+            },
+            DATA_ENTRY | DATA_ENTRY_FINE if self.parameters.get(&channel) == Some(&PITCH_BEND_RANGE) => {
+                self.bend_range_changes.entry(channel)
+                    .or_insert_with(Vec::new)
+                    .push((self.current_time, control, value));
+            },
+            _ => {
+                // currently don't care about other controllers
+            },
+        }
+    }
+
+    fn pitch_bend(&mut self, channel: u8) {
+        // see `scan_pitch_bends` for why ghakuf's value isn't used, a bend that wasn't scanned is
+        // dropped rather than played as whatever it decoded
+        let value = self.current_track.checked_sub(1)
+            .and_then(|track| self.pitch_bends.get(track))
+            .and_then(|bends| bends.get(self.bends_in_track))
+            .cloned();
+        self.bends_in_track += 1;
+        let value = match value {
+            Some(value) => value,
+            None => return,
+        };
+
+        self.events.push(MidiEvent::PitchBend {
+            track: self.current_track,
+            channel: channel + 1, // remember that from in MIDI channels are 1-indexed
+            value,
+            range_cents: DEFAULT_BEND_RANGE_CENTS, // see `into_music`
+            start: self.current_time,
+        }, self.current_time);
+    }
+
     fn note_ended(&mut self, channel: u8, note: u8) {
         let key = (channel, note);
         if self.book_keeping.contains_key(&key) {
@@ -280,6 +896,7 @@ impl Handler {
                 start: start_of_note.start,
                 duration: Ticks(self.current_time.0 - start_of_note.start.0),
                 velocity: start_of_note.velocity,
+                program: 0, // see `into_music`
             };
             self.events.push(played, start_of_note.start);
             self.book_keeping.remove(&key);
@@ -290,6 +907,11 @@ impl Handler {
 impl ghakuf::reader::Handler for Handler {
     fn header(&mut self, format: u16, track: u16, time_base: u16) {
         self.handled += 1;
+        self.header = Some(Header {
+            format,
+            tracks: track,
+            time_base,
+        });
         self.division = time_base as f64;
         if self.verbose {
             println!("{:>4} [header] format: {}, track: {}, time_base: {}", self.handled, format, track, time_base);
@@ -298,6 +920,9 @@ impl ghakuf::reader::Handler for Handler {
 
     fn meta_event(&mut self, delta_time: u32, event: &MetaEvent, data: &Vec<u8>) {
         self.handled += 1;
+        // as with every other event, the delta is the time since the last one
+        self.advance_time(delta_time);
+
         match event {
             &MetaEvent::SetTempo => {
                 if data.len() == 3 {
@@ -323,17 +948,34 @@ impl ghakuf::reader::Handler for Handler {
             }
 
             &MetaEvent::TextEvent => {
-                // println!("{:>4} [meta] text event: {}", self.handled, slice_to_text(data));
+                self.metadata.text.push(slice_to_text(data));
             }
 
             &MetaEvent::CopyrightNotice => {
-                // println!("{:>4} [meta] copyright: {}", self.handled, slice_to_text(data));
+                if self.metadata.copyright.is_none() {
+                    self.metadata.copyright = Some(slice_to_text(data));
+                }
             }
 
             &MetaEvent::SequenceOrTrackName => {
+                let name = slice_to_text(data);
                 if self.verbose {
-                    println!("{:>4} [meta] seq/track name: {}", self.handled, slice_to_text(data));
+                    println!("{:>4} [meta] seq/track name: {}", self.handled, name);
                 }
+
+                // in a format 1 file the first track's name is the sequence's
+                if self.current_track == 1 && self.metadata.title.is_none() {
+                    self.metadata.title = Some(name.clone());
+                }
+                self.metadata.track_names.entry(self.current_track)
+                    .or_insert(name);
+            }
+
+            &MetaEvent::Marker => {
+                self.events.push(MidiEvent::Marker {
+                    text: slice_to_text(data),
+                    start: self.current_time,
+                }, self.current_time);
             }
 
             &MetaEvent::MIDIChannelPrefix => {
@@ -348,7 +990,15 @@ impl ghakuf::reader::Handler for Handler {
             }
 
             &MetaEvent::KeySignature => {
-                // don't currently care about this
+                if data.len() == 2 {
+                    self.events.push(MidiEvent::KeySignature {
+                        sharps: data[0] as i8,
+                        minor: data[1] == 1,
+                        start: self.current_time,
+                    }, self.current_time);
+                } else {
+                    println!("{:>4} [meta] event: {}, data: {:?} - data length isn't 2!?", self.handled, event, data);
+                }
             }
 
             &MetaEvent::EndOfTrack => {
@@ -368,7 +1018,6 @@ impl ghakuf::reader::Handler for Handler {
                 }
             },
         }
-        self.advance_time(delta_time);
     }
 
     fn midi_event(&mut self, delta_time: u32, event: &messages::MidiEvent) {
@@ -389,17 +1038,20 @@ impl ghakuf::reader::Handler for Handler {
             },
 
             &messages::MidiEvent::ProgramChange { ch, program } => {
+                self.program_changes.entry(ch)
+                    .or_insert_with(Vec::new)
+                    .push((self.current_time, program));
                 if self.verbose {
                     println!("{:>4} [midi] program change [channel {}]: {} ({:?})", self.handled, ch + 1, program + 1, InstrumentFamily::from_program(program));
                 }
             }
 
-            &messages::MidiEvent::ControlChange { .. } => {
-                // currently don't care about this
+            &messages::MidiEvent::ControlChange { ch, control, data } => {
+                self.control_change(ch, control, data);
             },
 
-            &messages::MidiEvent::PitchBendChange { .. } => {
-                // currently don't care about this
+            &messages::MidiEvent::PitchBendChange { ch, .. } => {
+                self.pitch_bend(ch);
             },
 
             _ => {
@@ -420,12 +1072,155 @@ impl ghakuf::reader::Handler for Handler {
 
     fn track_change(&mut self) {
         self.handled += 1;
+        if self.current_track > 0 {
+            self.track_ends.insert(self.current_track, self.current_time);
+        }
+        self.bends_in_track = 0;
         // println!("{:>4} [track_change] resetting current time", self.handled);
         self.current_time = Ticks(0);
         self.current_track += 1;
     }
 }
 
+/// A channel's pitch bend range in cents at the given time, from the data entry controller changes
+/// to it so far. The coarse controller sets the semitones and the fine one the cents.
+fn bend_range_at(changes: &[(Ticks, u8, u8)], at: Ticks) -> u16 {
+    changes.iter()
+        .take_while(|&&(time, _, _)| time <= at)
+        .fold(DEFAULT_BEND_RANGE_CENTS, |range, &(_, control, value)| if control == DATA_ENTRY {
+            value as u16 * 100 + range % 100
+        } else {
+            range - range % 100 + min(value, 99) as u16
+        })
+}
+
+/// When a note let go of at `released` stops sounding, as some pedal on its channel may be holding
+/// it on. A pedal that's never let up holds it to the end of its track.
+fn pedalled_until(pedal_changes: &HashMap<(u8, u8), Vec<(Ticks, bool)>>, channel: u8, start: Ticks, released: Ticks, track_end: Ticks) -> Ticks {
+    let no_changes = Vec::new();
+    let sustain = pedal_changes.get(&(channel, SUSTAIN_PEDAL)).unwrap_or(&no_changes);
+    let sostenuto = pedal_changes.get(&(channel, SOSTENUTO_PEDAL)).unwrap_or(&no_changes);
+
+    // one pedal can take over holding a note from the other, so keep going until neither does
+    let mut end = released;
+    loop {
+        let mut until = end;
+        if let Some((_, lifted)) = pedal_down_at(sustain, end, track_end) {
+            until = max(until, lifted);
+        }
+        if let Some((pressed, lifted)) = pedal_down_at(sostenuto, end, track_end) {
+            // only notes still held down are caught, not ones already let go of
+            if start <= pressed && released > pressed {
+                until = max(until, lifted);
+            }
+        }
+
+        if until == end {
+            return end;
+        }
+        end = until;
+    }
+}
+
+/// Where a pedal is down at the given time, finds when it was pressed and when it's next let up.
+fn pedal_down_at(changes: &[(Ticks, bool)], at: Ticks, track_end: Ticks) -> Option<(Ticks, Ticks)> {
+    let index = changes.iter().rposition(|&(time, _)| time <= at)?;
+    if changes[index].1 == false {
+        return None;
+    }
+
+    // pressing a pedal that's already down changes nothing
+    let pressed = changes[..index].iter()
+        .rposition(|&(_, down)| down == false)
+        .map(|up| changes[up + 1].0)
+        .unwrap_or(changes[0].0);
+    let lifted = changes[index..].iter()
+        .find(|&&(_, down)| down == false)
+        .map(|&(time, _)| time)
+        .unwrap_or(max(track_end, at));
+
+    Some((pressed, lifted))
+}
+
+/// Finds the value of every pitch bend in each track of a file, in the order they appear. ghakuf
+/// decodes every pitch bend as -8192, masking its two data bytes together rather than combining
+/// them, so the values are read from the file directly and matched up with the bends it reports.
+fn scan_pitch_bends(path: &Path) -> io::Result<Vec<Vec<i16>>> {
+    let mut data = Vec::new();
+    File::open(path)?.read_to_end(&mut data)?;
+
+    let truncated = || io::Error::new(io::ErrorKind::UnexpectedEof, "file ends part way through a chunk");
+    let byte = |position: usize| data.get(position).cloned().ok_or_else(truncated);
+    let variable_length = |position: &mut usize| -> io::Result<usize> {
+        let mut value = 0usize;
+        loop {
+            let next = byte(*position)?;
+            *position += 1;
+            value = (value << 7) | (next & 0x7f) as usize;
+            if next & 0x80 == 0 {
+                return Ok(value);
+            }
+        }
+    };
+
+    let mut tracks = Vec::new();
+    let mut position = 0;
+    while position + 8 <= data.len() {
+        let tag = &data[position..position + 4];
+        let length = ((data[position + 4] as usize) << 24)
+            | ((data[position + 5] as usize) << 16)
+            | ((data[position + 6] as usize) << 8)
+            | (data[position + 7] as usize);
+        let end = position + 8 + length;
+        position += 8;
+
+        if tag != b"MTrk" {
+            position = end;
+            continue;
+        }
+
+        let mut bends = Vec::new();
+        let mut running_status = 0u8;
+        while position < min(end, data.len()) {
+            variable_length(&mut position)?;
+
+            let mut status = byte(position)?;
+            if status < 0x80 {
+                status = running_status;
+            } else {
+                position += 1;
+            }
+
+            match status {
+                0xff => {
+                    position += 1;
+                    let length = variable_length(&mut position)?;
+                    position += length;
+                },
+                0xf0 | 0xf7 => {
+                    let length = variable_length(&mut position)?;
+                    position += length;
+                },
+                0x80..=0xef => {
+                    running_status = status;
+                    let length = if status & 0xe0 == 0xc0 { 1 } else { 2 };
+                    if status & 0xf0 == 0xe0 {
+                        let value = ((byte(position + 1)? as i16) << 7) | byte(position)? as i16;
+                        bends.push(value - 8192);
+                    }
+                    position += length;
+                },
+                _ => return Err(io::Error::new(io::ErrorKind::InvalidData, "unknown status byte")),
+            }
+        }
+
+        tracks.push(bends);
+        position = end;
+    }
+
+    Ok(tracks)
+}
+
 fn slice_to_text(text: &[u8]) -> String {
     String::from_utf8(text.to_vec()).unwrap_or("<failed to decode text>".into())
 }
@@ -0,0 +1,144 @@
+use connection::{ClientUID, Codec, FrameError};
+use packet::Packet;
+
+use std::net::{UdpSocket, SocketAddrV4, Ipv4Addr};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::io::ErrorKind;
+use std::time::Duration;
+use std::thread::spawn;
+use std::sync::Arc;
+use std::fmt;
+use std::io;
+
+use net2::UdpBuilder;
+
+/// Large enough for any UDP datagram.
+const MAX_DATAGRAM_SIZE: usize = 65536;
+
+/// Frames larger than this aren't multicast, as a datagram much bigger than an ethernet frame is
+/// fragmented and lost entirely if any one fragment is.
+pub const MAX_MULTICAST_FRAME_SIZE: usize = 1400;
+
+/// How often the receiver thread wakes to check whether it should stop.
+const RECEIVE_TIMEOUT: Duration = Duration::from_millis(500);
+
+/// Sends packets to every client at once, each datagram naming the clients it is meant for.
+pub struct MulticastSender {
+    socket: UdpSocket,
+    group: SocketAddrV4,
+    codec: Codec,
+}
+
+#[derive(Debug)]
+pub enum MulticastError {
+    /// The packet is too large to multicast, and should be sent to each client another way.
+    TooLarge {
+        length: usize,
+    },
+    Frame(FrameError),
+}
+
+impl fmt::Display for MulticastError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            MulticastError::TooLarge { length } => write!(f, "frame of {} bytes exceeds multicast limit of {} bytes", length, MAX_MULTICAST_FRAME_SIZE),
+            MulticastError::Frame(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl From<FrameError> for MulticastError {
+    fn from(e: FrameError) -> Self {
+        MulticastError::Frame(e)
+    }
+}
+
+impl From<io::Error> for MulticastError {
+    fn from(e: io::Error) -> Self {
+        MulticastError::Frame(FrameError::Io(e))
+    }
+}
+
+impl MulticastSender {
+    pub fn new(group: SocketAddrV4, codec: Codec) -> io::Result<Self> {
+        let socket = UdpSocket::bind("0.0.0.0:0")?;
+        // the orchestra shares a LAN, there's no reason for notes to leave it
+        socket.set_multicast_ttl_v4(1)?;
+
+        Ok(Self {
+            socket,
+            group,
+            codec,
+        })
+    }
+
+    pub fn send(&self, targets: Vec<ClientUID>, packet: Packet) -> Result<(), MulticastError> {
+        let mut datagram = Vec::new();
+        self.codec.send(&mut datagram, &Packet::Addressed {
+            targets,
+            packet: Box::new(packet),
+        })?;
+        if datagram.len() > MAX_MULTICAST_FRAME_SIZE {
+            return Err(MulticastError::TooLarge { length: datagram.len() });
+        }
+
+        self.socket.send_to(&datagram, self.group)?;
+        Ok(())
+    }
+}
+
+/// Listens to the multicast group on a dedicated thread until dropped.
+pub struct MulticastReceiver {
+    stopped: Arc<AtomicBool>,
+}
+
+impl MulticastReceiver {
+    /// Joins the group, passing every packet addressed to `uid` to `handler`.
+    pub fn start<F>(group: SocketAddrV4, uid: ClientUID, codec: Codec, mut handler: F) -> io::Result<Self>
+        where F: FnMut(Packet) + Send + 'static {
+        // several clients on one machine need to share the port
+        let socket = UdpBuilder::new_v4()?
+            .reuse_address(true)?
+            .bind(("0.0.0.0", group.port()))?;
+        socket.join_multicast_v4(group.ip(), &Ipv4Addr::new(0, 0, 0, 0))?;
+        socket.set_read_timeout(Some(RECEIVE_TIMEOUT))?;
+
+        let stopped = Arc::new(AtomicBool::new(false));
+        let stopped_for_thread = stopped.clone();
+
+        spawn(move || {
+            let mut buffer = vec![0u8; MAX_DATAGRAM_SIZE];
+
+            while stopped_for_thread.load(Ordering::SeqCst) == false {
+                let length = match socket.recv_from(&mut buffer) {
+                    Ok((length, _)) => length,
+                    Err(ref e) if e.kind() == ErrorKind::WouldBlock || e.kind() == ErrorKind::TimedOut => continue,
+                    Err(e) => {
+                        println!("multicast receive failed: {}", e);
+                        break;
+                    },
+                };
+
+                match codec.recv(&buffer[..length]) {
+                    Ok(Packet::Addressed { targets, packet }) => {
+                        if targets.contains(&uid) {
+                            handler(*packet);
+                        }
+                    },
+                    Ok(packet) => println!("ignoring unaddressed multicast packet: {:?}", packet),
+                    Err(e) => println!("discarding bad multicast datagram: {}", e),
+                }
+            }
+        });
+
+        Ok(Self {
+            stopped,
+        })
+    }
+}
+
+impl Drop for MulticastReceiver {
+    fn drop(&mut self) {
+        self.stopped.store(true, Ordering::SeqCst);
+    }
+}
@@ -1,19 +1,116 @@
+use connection::ClientUID;
 
-#[derive(Serialize, Deserialize, PartialEq, Debug)]
+/// Bumped whenever the packet layout changes, so mismatched builds are rejected at handshake.
+/// Inserting a variant counts, as bincode identifies variants by their position.
+pub const PROTOCOL_VERSION: u32 = 7;
+
+#[derive(Serialize, Deserialize, Copy, Clone, PartialEq, Eq, Debug)]
+pub enum Waveform {
+    Square,
+}
+
+#[derive(Serialize, Deserialize, Clone, PartialEq, Debug)]
+pub struct Capabilities {
+    pub max_polyphony: u32,
+    pub waveforms: Vec<Waveform>,
+    pub lowest_frequency: f32,
+    pub highest_frequency: f32,
+}
+
+#[derive(Serialize, Deserialize, Clone, PartialEq, Debug)]
+pub struct BatchedNote {
+    /// Nanoseconds after the start time of the batch containing this note.
+    pub start_offset: u64,
+    pub duration: u64,
+    pub frequency: f32,
+    pub volume: f32,
+    /// The MIDI channel the note came from, which pitch bends are applied by.
+    pub channel: u8,
+}
+
+/// Timestamps are nanoseconds on the server's clock, which clients estimate their offset from
+/// using the `ClockSync*` exchanges immediately after sending `ClientInfo`.
+#[derive(Serialize, Deserialize, Clone, PartialEq, Debug)]
 pub enum Packet {
-    ClientInfo,
+    ClientInfo {
+        protocol_version: u32,
+        name: String,
+        capabilities: Capabilities,
+    },
+    Accept {
+        uid: ClientUID,
+    },
+    Reject {
+        reason: String,
+    },
+    ClockSyncRequest {
+        client_send_time: u64,
+    },
+    ClockSyncResponse {
+        client_send_time: u64,
+        server_receive_time: u64,
+        server_send_time: u64,
+    },
+    ClockSyncComplete,
+    /// Tells a client to also listen for timed packets on the given multicast group.
+    UseMulticast {
+        group: String,
+    },
+    /// Wraps a packet multicast to every client, naming the ones that should act on it.
+    Addressed {
+        targets: Vec<ClientUID>,
+        packet: Box<Packet>,
+    },
+    Ping {
+        server_time: u64,
+    },
+    Pong {
+        server_time: u64,
+    },
     PlayNote {
+        start_time: u64,
         duration: u64,
         frequency: f32,
         volume: f32,
+        channel: u8,
+    },
+    /// Starts a note that sounds until the `NoteOff` with the same id, for notes whose end isn't
+    /// known when they begin.
+    NoteOn {
+        id: u32,
+        start_time: u64,
+        frequency: f32,
+        volume: f32,
+        channel: u8,
+    },
+    NoteOff {
+        id: u32,
+        end_time: u64,
+    },
+    /// Every note a client is to play within one lookahead window.
+    NoteBatch {
+        start_time: u64,
+        notes: Vec<BatchedNote>,
+    },
+    /// Bends every note from the given channel, sounding then or started later, by this many
+    /// semitones until the channel's next bend.
+    PitchBend {
+        start_time: u64,
+        channel: u8,
+        semitones: f32,
     },
+    /// Stop every note sounding or scheduled, e.g. because playback was paused or moved.
+    Silence,
     TerminateAfter(u64),
 }
 
 impl Packet {
     pub fn is_client_message(&self) -> bool {
         match self {
-            &Packet::ClientInfo => true,
+            &Packet::ClientInfo { .. } => true,
+            &Packet::ClockSyncRequest { .. } => true,
+            &Packet::ClockSyncComplete => true,
+            &Packet::Pong { .. } => true,
             _ => false,
         }
     }
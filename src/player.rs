@@ -0,0 +1,115 @@
+use beep::{Beeper, Voice};
+use schedule::Schedule;
+
+use std::sync::mpsc::{channel, Sender, Receiver, RecvTimeoutError};
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+use std::thread::spawn;
+
+use pitch_calc::Hz;
+
+pub enum Action {
+    Beep {
+        frequency: Hz,
+        duration: Duration,
+        volume: f32,
+        channel: u8,
+    },
+    NoteOn {
+        id: u32,
+        frequency: Hz,
+        volume: f32,
+        channel: u8,
+    },
+    NoteOff {
+        id: u32,
+    },
+    PitchBend {
+        channel: u8,
+        semitones: f32,
+    },
+    /// Stops everything sounding and forgets everything scheduled.
+    Silence,
+}
+
+/// Performs actions at requested instants on a dedicated thread, so that the network loop never
+/// blocks waiting for a note to be due.
+#[derive(Clone)]
+pub struct Player {
+    sender: Sender<(Instant, Action)>,
+}
+
+impl Player {
+    pub fn new() -> Self {
+        let (sender, receiver) = channel();
+
+        spawn(move || run(receiver));
+
+        Self {
+            sender,
+        }
+    }
+
+    pub fn schedule(&self, at: Instant, action: Action) {
+        self.sender.send((at, action))
+            .expect("player thread has stopped");
+    }
+}
+
+fn run(receiver: Receiver<(Instant, Action)>) {
+    let beeper = Beeper::new();
+    let mut schedule = Schedule::new();
+    // dropping a voice silences it, so anything still sounding stops when the player does
+    let mut voices = HashMap::new();
+
+    loop {
+        let received = match schedule.next_due() {
+            Some(at) => {
+                let now = Instant::now();
+                let timeout = if at > now { at - now } else { Duration::new(0, 0) };
+                receiver.recv_timeout(timeout)
+            },
+            None => receiver.recv()
+                .map_err(|_| RecvTimeoutError::Disconnected),
+        };
+
+        match received {
+            Ok((at, action)) => schedule.push(at, action),
+            Err(RecvTimeoutError::Timeout) => {},
+            Err(RecvTimeoutError::Disconnected) => break,
+        }
+
+        let now = Instant::now();
+        while let Some(action) = schedule.pop_due(now) {
+            if let Action::Silence = action {
+                schedule = Schedule::new();
+                voices.clear();
+                beeper.silence();
+                continue;
+            }
+
+            perform(&beeper, &mut voices, action);
+        }
+    }
+}
+
+fn perform(beeper: &Beeper, voices: &mut HashMap<u32, Voice>, action: Action) {
+    match action {
+        Action::Beep { frequency, duration, volume, channel } => {
+            beeper.beep(frequency, duration, volume, channel);
+        },
+        Action::NoteOn { id, frequency, volume, channel } => {
+            // replacing a voice with the same id drops, and so silences, the old one
+            voices.insert(id, beeper.start(frequency, volume, channel));
+        },
+        Action::NoteOff { id } => {
+            voices.remove(&id);
+        },
+        Action::PitchBend { channel, semitones } => {
+            beeper.bend(channel, semitones);
+        },
+        Action::Silence => {
+            // handled by `run`, which owns the schedule that also needs clearing
+        },
+    }
+}
@@ -0,0 +1,126 @@
+use std::path::{Path, PathBuf};
+use std::io::{BufRead, BufReader};
+use std::fs::{self, File};
+
+use rand::{thread_rng, Rng};
+
+/// The songs to play, in the order they're to be played.
+pub struct Playlist {
+    songs: Vec<PathBuf>,
+    shuffle: bool,
+    repeat: bool,
+    position: usize,
+}
+
+impl Playlist {
+    pub fn new(songs: Vec<PathBuf>, shuffle: bool, repeat: bool) -> Self {
+        let mut playlist = Self {
+            songs,
+            shuffle,
+            repeat,
+            position: 0,
+        };
+        playlist.reorder();
+
+        playlist
+    }
+
+    pub fn len(&self) -> usize {
+        self.songs.len()
+    }
+
+    /// Moves on to the next song, going back to the start (and reshuffling) when repeating.
+    pub fn next(&mut self) -> Option<PathBuf> {
+        if self.position >= self.songs.len() {
+            if self.repeat == false || self.songs.is_empty() {
+                return None;
+            }
+
+            self.position = 0;
+            self.reorder();
+        }
+
+        let song = self.songs[self.position].clone();
+        self.position += 1;
+        Some(song)
+    }
+
+    fn reorder(&mut self) {
+        if self.shuffle {
+            thread_rng().shuffle(&mut self.songs);
+        }
+    }
+}
+
+/// Turns the paths given on the command line into a list of songs, where each path is a MIDI
+/// file, a directory of them, or an m3u playlist listing them.
+pub fn expand_paths<P: AsRef<Path>>(paths: &[P]) -> Result<Vec<PathBuf>, String> {
+    let mut songs = Vec::new();
+
+    for path in paths {
+        let path = path.as_ref();
+
+        if path.is_dir() {
+            let entries = fs::read_dir(path)
+                .map_err(|e| format!("unable to read directory {}: {}", path.display(), e))?;
+
+            let mut found = entries
+                .filter_map(|entry| entry.ok())
+                .map(|entry| entry.path())
+                .filter(|path| path.is_file() && is_midi(path))
+                .collect::<Vec<_>>();
+            found.sort();
+
+            if found.is_empty() {
+                println!("warning: no MIDI files found in {}", path.display());
+            }
+            songs.extend(found);
+        } else if is_m3u(path) {
+            songs.extend(read_m3u(path)?);
+        } else if path.is_file() {
+            songs.push(path.to_path_buf());
+        } else {
+            return Err(format!("no such file or directory: {}", path.display()));
+        }
+    }
+
+    Ok(songs)
+}
+
+fn read_m3u(path: &Path) -> Result<Vec<PathBuf>, String> {
+    let file = File::open(path)
+        .map_err(|e| format!("unable to open playlist {}: {}", path.display(), e))?;
+    // entries are relative to the playlist itself
+    let base = path.parent()
+        .unwrap_or(Path::new(""));
+
+    let mut songs = Vec::new();
+    for line in BufReader::new(file).lines() {
+        let line = line
+            .map_err(|e| format!("unable to read playlist {}: {}", path.display(), e))?;
+        let line = line.trim();
+
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+
+        songs.push(base.join(line));
+    }
+
+    Ok(songs)
+}
+
+fn is_midi(path: &Path) -> bool {
+    has_extension(path, &["mid", "midi"])
+}
+
+fn is_m3u(path: &Path) -> bool {
+    has_extension(path, &["m3u", "m3u8"])
+}
+
+fn has_extension(path: &Path, extensions: &[&str]) -> bool {
+    path.extension()
+        .and_then(|extension| extension.to_str())
+        .map(|extension| extensions.iter().any(|e| e.eq_ignore_ascii_case(extension)))
+        .unwrap_or(false)
+}
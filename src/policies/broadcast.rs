@@ -1,5 +1,5 @@
 use super::super::connection::{ClientUID, ClientInfo};
-use super::ClientSelectionPolicy;
+use super::{ClientSelectionPolicy, Assignment};
 use super::super::midi::Note;
 
 pub struct BroadcastPolicy {
@@ -23,4 +23,15 @@ impl ClientSelectionPolicy for BroadcastPolicy {
     fn select_clients(&self, _note: &Note) -> Vec<ClientUID> {
         self.all.clone()
     }
+
+    fn assignments(&self) -> Vec<Assignment> {
+        if self.all.is_empty() {
+            return vec![];
+        }
+
+        vec![Assignment {
+            part: "everything".to_string(),
+            clients: self.all.clone(),
+        }]
+    }
 }
@@ -1,8 +1,10 @@
 use std::collections::{HashSet, HashMap};
 
+use itertools::Itertools;
+
 use super::super::connection::{ClientUID, ClientInfo};
 use super::super::midi::{Note, MusicalEvent};
-use super::ClientSelectionPolicy;
+use super::{ClientSelectionPolicy, Assignment};
 
 pub struct ByChannelPolicy {
     channels: HashSet<u8>,
@@ -32,17 +34,12 @@ impl ByChannelPolicy {
 impl ClientSelectionPolicy for ByChannelPolicy {
     fn on_clients_changed(&mut self, clients: &[ClientInfo]) {
         let mut assignments = HashMap::new();
-        for (index, channel) in self.channels.iter().enumerate() {
-            assignments.insert(*channel, clients[index % clients.len()].uid.clone());
-        }
-        self.assignments = assignments;
-
-        if self.assignments.len() > 0 {
-            println!("assignments:");
-            for (channel, uid) in self.assignments.iter() {
-                println!("  channel {} => {:?}", channel, uid);
+        if clients.len() > 0 {
+            for (index, channel) in self.channels.iter().enumerate() {
+                assignments.insert(*channel, clients[index % clients.len()].uid.clone());
             }
         }
+        self.assignments = assignments;
     }
 
     fn select_clients(&self, note: &Note) -> Vec<ClientUID> {
@@ -51,4 +48,15 @@ impl ClientSelectionPolicy for ByChannelPolicy {
             _ => vec![],
         }
     }
+
+    fn assignments(&self) -> Vec<Assignment> {
+        self.assignments.iter()
+            .sorted_by(|a, b| a.0.cmp(b.0))
+            .into_iter()
+            .map(|(channel, uid)| Assignment {
+                part: format!("channel {}", channel),
+                clients: vec![*uid],
+            })
+            .collect()
+    }
 }
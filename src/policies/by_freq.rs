@@ -4,7 +4,7 @@ use itertools::Itertools;
 
 use super::super::connection::{ClientUID, ClientInfo};
 use super::super::midi::{Note, MusicalEvent};
-use super::ClientSelectionPolicy;
+use super::{ClientSelectionPolicy, Assignment};
 
 struct FrequencyRangeAssignment {
     lowest: u8,
@@ -81,18 +81,6 @@ impl ClientSelectionPolicy for ByFrequencyPolicy {
                 }
             }
 
-            if new_assignments.len() > 0 {
-                println!("assignments:");
-                for assignment in new_assignments.iter() {
-                    println!(
-                        "  notes [{} -> {}] => {:?}",
-                        assignment.lowest,
-                        assignment.highest,
-                        assignment.client
-                    );
-                }
-            }
-
             new_assignments
         } else {
             vec![]
@@ -107,4 +95,13 @@ impl ClientSelectionPolicy for ByFrequencyPolicy {
             .map(|assignment| assignment.client.clone())
             .collect::<Vec<_>>()
     }
+
+    fn assignments(&self) -> Vec<Assignment> {
+        self.assignments.iter()
+            .map(|assignment| Assignment {
+                part: format!("notes [{} -> {}]", assignment.lowest, assignment.highest),
+                clients: vec![assignment.client],
+            })
+            .collect()
+    }
 }
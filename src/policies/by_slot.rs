@@ -0,0 +1,49 @@
+use super::super::connection::{ClientUID, ClientInfo};
+use super::super::midi::Note;
+use super::{ClientSelectionPolicy, Assignment};
+
+/// Shares the slots of a rendering between clients, for describing who is playing what while
+/// it's replayed. Rendered packets already name their slot, so there are no notes to select for.
+pub struct BySlotPolicy {
+    slots: usize,
+    clients: Vec<ClientUID>,
+}
+
+impl BySlotPolicy {
+    pub fn new(slots: usize) -> Self {
+        Self {
+            slots,
+            clients: Vec::new(),
+        }
+    }
+}
+
+/// Which client plays a slot, going round the clients again when there are more slots than them.
+pub fn client_for_slot(clients: &[ClientUID], slot: usize) -> Option<ClientUID> {
+    if clients.is_empty() {
+        return None;
+    }
+
+    Some(clients[slot % clients.len()])
+}
+
+impl ClientSelectionPolicy for BySlotPolicy {
+    fn on_clients_changed(&mut self, clients: &[ClientInfo]) {
+        self.clients = clients.iter()
+            .map(|c| c.uid)
+            .collect();
+    }
+
+    fn select_clients(&self, _note: &Note) -> Vec<ClientUID> {
+        Vec::new()
+    }
+
+    fn assignments(&self) -> Vec<Assignment> {
+        (0..self.slots)
+            .filter_map(|slot| client_for_slot(&self.clients, slot).map(|uid| Assignment {
+                part: format!("slot {}", slot),
+                clients: vec![uid],
+            }))
+            .collect()
+    }
+}
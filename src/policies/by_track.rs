@@ -1,8 +1,10 @@
 use std::collections::{HashSet, HashMap};
 
+use itertools::Itertools;
+
 use super::super::connection::{ClientUID, ClientInfo};
 use super::super::midi::{Note, MusicalEvent};
-use super::ClientSelectionPolicy;
+use super::{ClientSelectionPolicy, Assignment};
 
 pub struct ByTrackPolicy {
     tracks: HashSet<usize>,
@@ -32,17 +34,12 @@ impl ByTrackPolicy {
 impl ClientSelectionPolicy for ByTrackPolicy {
     fn on_clients_changed(&mut self, clients: &[ClientInfo]) {
         let mut assignments = HashMap::new();
-        for (index, track) in self.tracks.iter().enumerate() {
-            assignments.insert(*track, clients[index % clients.len()].uid.clone());
-        }
-        self.assignments = assignments;
-
-        if self.assignments.len() > 0 {
-            println!("assignments:");
-            for (channel, uid) in self.assignments.iter() {
-                println!("  track {} => {:?}", channel, uid);
+        if clients.len() > 0 {
+            for (index, track) in self.tracks.iter().enumerate() {
+                assignments.insert(*track, clients[index % clients.len()].uid.clone());
             }
         }
+        self.assignments = assignments;
     }
 
     fn select_clients(&self, note: &Note) -> Vec<ClientUID> {
@@ -51,4 +48,15 @@ impl ClientSelectionPolicy for ByTrackPolicy {
             _ => vec![],
         }
     }
+
+    fn assignments(&self) -> Vec<Assignment> {
+        self.assignments.iter()
+            .sorted_by(|a, b| a.0.cmp(b.0))
+            .into_iter()
+            .map(|(track, uid)| Assignment {
+                part: format!("track {}", track),
+                clients: vec![*uid],
+            })
+            .collect()
+    }
 }
@@ -3,6 +3,7 @@ mod by_channel;
 mod broadcast;
 mod by_track;
 mod by_freq;
+mod by_slot;
 
 use super::connection::{ClientUID, ClientInfo};
 use super::midi::{MusicalEvent, Note};
@@ -10,10 +11,20 @@ use self::by_channel::ByChannelPolicy;
 use self::by_freq::ByFrequencyPolicy;
 use self::broadcast::BroadcastPolicy;
 use self::by_track::ByTrackPolicy;
+pub use self::by_slot::{BySlotPolicy, client_for_slot};
+
+/// A part of the music, and the clients currently playing it.
+#[derive(Serialize, Clone, Debug)]
+pub struct Assignment {
+    pub part: String,
+    pub clients: Vec<ClientUID>,
+}
 
 pub trait ClientSelectionPolicy: Send {
     fn on_clients_changed(&mut self, clients: &[ClientInfo]);
     fn select_clients(&self, note: &Note) -> Vec<ClientUID>;
+    /// Describes who is playing what, as of the last change of clients.
+    fn assignments(&self) -> Vec<Assignment>;
 }
 
 pub fn select_policy(name: String, events: &[MusicalEvent]) -> Option<Box<ClientSelectionPolicy>> {
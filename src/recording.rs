@@ -0,0 +1,242 @@
+use connection::ClientUID;
+use packet::Packet;
+use clock::Clock;
+use convert_duration::*;
+
+use std::io::{BufRead, BufReader, LineWriter, Write};
+use std::fs::{File, OpenOptions};
+use std::collections::BTreeMap;
+use std::cmp::{min, max};
+use std::time::{SystemTime, UNIX_EPOCH};
+use std::sync::{Arc, Mutex};
+use std::path::Path;
+use std::io;
+
+use clap::ArgMatches;
+use serde_json;
+
+/// One packet sent by the server, as written to a recording, one JSON object per line.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct Entry {
+    /// When the packet was written to the network, on the server's clock.
+    pub sent: u64,
+    /// When it was meant to be sent, for packets sent on a schedule.
+    pub scheduled: Option<u64>,
+    pub clients: Vec<ClientUID>,
+    pub packet: Packet,
+}
+
+/// Written whenever a server opens a recording. Every run's clock starts again from zero and its
+/// clients are numbered from one again, so entries can only be compared within a session.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct SessionStart {
+    /// Seconds since the Unix epoch, on the server's wall clock.
+    pub session_started: u64,
+}
+
+/// Any line of a recording.
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum Line {
+    SessionStart(SessionStart),
+    Entry(Entry),
+}
+
+/// Appends every packet the server sends to a file, for working out afterwards what went wrong.
+#[derive(Clone)]
+pub struct Recorder {
+    clock: Clock,
+    file: Arc<Mutex<LineWriter<File>>>,
+}
+
+impl Recorder {
+    pub fn create<P: AsRef<Path>>(path: P, clock: Clock) -> io::Result<Self> {
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)?;
+        let mut file = LineWriter::new(file);
+
+        let session_started = SystemTime::now().duration_since(UNIX_EPOCH)
+            .map(|since| since.as_secs())
+            .unwrap_or(0);
+        let line = serde_json::to_string(&SessionStart { session_started })
+            .expect("failed to serialise session start");
+        writeln!(file, "{}", line)?;
+
+        Ok(Self {
+            clock,
+            file: Arc::new(Mutex::new(file)),
+        })
+    }
+
+    /// Records a packet as having just been sent.
+    pub fn record(&self, clients: Vec<ClientUID>, scheduled: Option<u64>, packet: &Packet) {
+        let entry = Entry {
+            sent: self.clock.now(),
+            scheduled,
+            clients,
+            packet: packet.clone(),
+        };
+
+        let line = serde_json::to_string(&entry)
+            .expect("failed to serialise recorded packet");
+
+        let mut file = self.file.lock()
+            .expect("failed to acquire recording mutex");
+        // a recording with gaps is better than a performance that stops over it
+        if let Err(e) = writeln!(file, "{}", line) {
+            println!("warning: failed to record packet: {}", e);
+        }
+    }
+}
+
+/// How one client's packets went, built up from a recording.
+struct ClientSummary {
+    packets: usize,
+    kinds: BTreeMap<String, usize>,
+    /// How late each scheduled packet was sent, in nanoseconds (negative if early).
+    lateness: Vec<i64>,
+}
+
+impl ClientSummary {
+    fn new() -> Self {
+        Self {
+            packets: 0,
+            kinds: BTreeMap::new(),
+            lateness: Vec::new(),
+        }
+    }
+}
+
+/// Everything recorded by one run of the server.
+struct SessionSummary {
+    /// Seconds since the Unix epoch, unknown for entries recorded before sessions were marked.
+    started: Option<u64>,
+    clients: BTreeMap<ClientUID, ClientSummary>,
+    first_sent: Option<u64>,
+    last_sent: Option<u64>,
+}
+
+impl SessionSummary {
+    fn new(started: Option<u64>) -> Self {
+        Self {
+            started,
+            clients: BTreeMap::new(),
+            first_sent: None,
+            last_sent: None,
+        }
+    }
+
+    fn add(&mut self, entry: Entry) {
+        self.first_sent = Some(self.first_sent.map(|first| min(first, entry.sent)).unwrap_or(entry.sent));
+        self.last_sent = Some(self.last_sent.map(|last| max(last, entry.sent)).unwrap_or(entry.sent));
+
+        let kind = packet_kind(&entry.packet);
+        for uid in entry.clients {
+            let summary = self.clients.entry(uid)
+                .or_insert_with(ClientSummary::new);
+
+            summary.packets += 1;
+            *summary.kinds.entry(kind.clone()).or_insert(0) += 1;
+            if let Some(scheduled) = entry.scheduled {
+                summary.lateness.push(entry.sent as i64 - scheduled as i64);
+            }
+        }
+    }
+
+    fn print(&mut self) {
+        if let (Some(first), Some(last)) = (self.first_sent, self.last_sent) {
+            println!("spans {:.1}s", duration_to_seconds(nanoseconds_to_duration(last.saturating_sub(first))));
+        }
+
+        println!("{:<14} {:>8} {:>10} {:>10} {:>10} {:>10}", "client", "packets", "mean ms", "jitter ms", "max ms", "p95 ms");
+        for (uid, summary) in self.clients.iter_mut() {
+            let (mean, jitter, latest, p95) = lateness_statistics(&mut summary.lateness);
+            println!(
+                "{:<14} {:>8} {:>10.3} {:>10.3} {:>10.3} {:>10.3}",
+                format!("{:?}", uid),
+                summary.packets,
+                mean,
+                jitter,
+                latest,
+                p95
+            );
+
+            let kinds = summary.kinds.iter()
+                .map(|(kind, count)| format!("{} {}", count, kind))
+                .collect::<Vec<_>>();
+            println!("  {}", kinds.join(", "));
+        }
+    }
+}
+
+/// Summarises a recording, showing what each client was sent and how far from schedule it was,
+/// separately for each time the server was run with it.
+pub fn summarise(matches: &ArgMatches) {
+    let path = matches.value_of("recording").unwrap();
+    let file = match File::open(path) {
+        Ok(file) => file,
+        Err(e) => {
+            println!("unable to open recording {}: {}", path, e);
+            return;
+        },
+    };
+
+    let mut sessions = vec![SessionSummary::new(None)];
+    let mut skipped = 0;
+
+    for line in BufReader::new(file).lines() {
+        match line.map(|line| serde_json::from_str::<Line>(&line)) {
+            Ok(Ok(Line::SessionStart(start))) => sessions.push(SessionSummary::new(Some(start.session_started))),
+            Ok(Ok(Line::Entry(entry))) => sessions.last_mut().unwrap().add(entry),
+            _ => skipped += 1,
+        }
+    }
+
+    if skipped > 0 {
+        println!("warning: skipped {} unreadable line(s)", skipped);
+    }
+
+    // entries from before sessions were marked only count if there are any
+    sessions.retain(|session| session.started.is_some() || session.first_sent.is_some());
+    let count = sessions.len();
+    for (index, session) in sessions.iter_mut().enumerate() {
+        match session.started {
+            Some(started) => println!("session {} of {}, started {}s after the Unix epoch", index + 1, count, started),
+            None => println!("session {} of {}, start unknown", index + 1, count),
+        }
+        session.print();
+        println!();
+    }
+}
+
+/// The mean, standard deviation, maximum and 95th percentile of how late packets were sent, all
+/// in milliseconds.
+fn lateness_statistics(lateness: &mut Vec<i64>) -> (f64, f64, f64, f64) {
+    if lateness.is_empty() {
+        return (0.0, 0.0, 0.0, 0.0);
+    }
+
+    lateness.sort();
+    let to_ms = |ns: f64| ns / 1_000_000.0;
+
+    let count = lateness.len() as f64;
+    let mean = lateness.iter().map(|l| *l as f64).sum::<f64>() / count;
+    let variance = lateness.iter()
+        .map(|l| (*l as f64 - mean).powi(2))
+        .sum::<f64>() / count;
+    let latest = *lateness.last().unwrap() as f64;
+    let p95 = lateness[((count * 0.95) as usize).min(lateness.len() - 1)] as f64;
+
+    (to_ms(mean), to_ms(variance.sqrt()), to_ms(latest), to_ms(p95))
+}
+
+/// The name of a packet's variant, e.g. `PlayNote`.
+fn packet_kind(packet: &Packet) -> String {
+    let debug = format!("{:?}", packet);
+    debug.split(|c: char| c == ' ' || c == '(' || c == '{')
+        .next()
+        .unwrap_or("")
+        .to_string()
+}
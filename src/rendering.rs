@@ -0,0 +1,252 @@
+use connection::ClientUID;
+use server::{parse_song_options, load_song};
+use simulate::virtual_clients;
+use packet::Packet;
+use policies::select_policy;
+use midi::MusicalEvent;
+use convert_duration::*;
+
+use std::io::{BufRead, BufReader, BufWriter, Write};
+use std::fs::File;
+use std::path::Path;
+use std::process;
+use std::cmp::max;
+
+use pitch_calc::Step;
+use clap::ArgMatches;
+use serde_json;
+
+/// A packet to send to whichever client ends up playing a slot, one JSON object per line of a
+/// rendering so that it can be edited and diffed by hand.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct RenderedPacket {
+    /// Nanoseconds after the start of the rendering that the packet takes effect.
+    pub at: u64,
+    pub slot: usize,
+    /// Timestamps within are also nanoseconds after the start of the rendering.
+    pub packet: Packet,
+}
+
+/// A song baked down to exactly what each client is told to play, see `bake`.
+pub struct Rendering {
+    pub name: String,
+    pub slots: usize,
+    pub packets: Vec<RenderedPacket>,
+}
+
+/// Runs a song through a policy for a number of client slots, writing every packet each slot
+/// would be sent to a file that the server can replay without the song or the policy.
+pub fn bake(matches: &ArgMatches) {
+    let path = matches.value_of("midi").unwrap();
+    let output = matches.value_of("output").unwrap();
+    let verbose = matches.is_present("verbose");
+    let policy_name = matches.value_of("policy").unwrap();
+    let slots: usize = match matches.value_of("clients").unwrap().parse() {
+        Ok(value) if value > 0 => value,
+        _ => {
+            println!("invalid client count, must be a whole number above zero");
+            return;
+        },
+    };
+    let volume_coefficient: f32 = match matches.value_of("volume").unwrap().parse() {
+        Ok(value) if value >= 0.0 && value <= 1.0 => value,
+        _ => {
+            println!("invalid volume value, must be between 0.0 and 1.0");
+            return;
+        },
+    };
+    let song_options = match parse_song_options(matches) {
+        Ok(options) => options,
+        Err(e) => {
+            println!("{}", e);
+            return;
+        },
+    };
+
+    let song = match load_song(Path::new(path), &song_options, verbose) {
+        Ok(song) => song,
+        Err(e) => {
+            println!("unable to load {}: {}", path, e);
+            process::exit(1);
+        },
+    };
+
+    let mut policy = select_policy(policy_name.to_string(), &song.events)
+        .expect("invalid policy");
+    println!("client selection policy: {}", policy_name);
+
+    // every slot can play any number of notes at once, it's up to whoever replays it to cope
+    let infos = virtual_clients(slots, u32::max_value());
+    policy.on_clients_changed(&infos);
+    let clients = infos.iter()
+        .map(|client| client.uid)
+        .collect::<Vec<_>>();
+
+    let mut packets = Vec::new();
+    let mut unassigned = 0;
+    for event in song.events.iter() {
+        if let MusicalEvent::PlayNote(note) = event {
+            // a region's notes are baked relative to the start of the region
+            if note.start_offset < song.start {
+                continue;
+            }
+            let at = duration_to_nanoseconds(note.start_offset - song.start);
+
+            let targets = policy.select_clients(note);
+            if targets.is_empty() {
+                unassigned += 1;
+            }
+
+            for uid in targets {
+                packets.push(RenderedPacket {
+                    at,
+                    slot: slot_of(&clients, uid),
+                    packet: Packet::PlayNote {
+                        start_time: at,
+                        duration: duration_to_nanoseconds(note.duration),
+                        frequency: Step(note.note as f32).to_hz().0,
+                        volume: (note.velocity as f32 / 128.0) * volume_coefficient,
+                        channel: note.channel,
+                    },
+                });
+            }
+        }
+
+        if let MusicalEvent::PitchBend(bend) = event {
+            if bend.start_offset < song.start {
+                continue;
+            }
+            let at = duration_to_nanoseconds(bend.start_offset - song.start);
+
+            // as when playing live, every slot hears about every bend
+            for slot in 0..slots {
+                packets.push(RenderedPacket {
+                    at,
+                    slot,
+                    packet: Packet::PitchBend {
+                        start_time: at,
+                        channel: bend.channel,
+                        semitones: bend.semitones(),
+                    },
+                });
+            }
+        }
+    }
+
+    if unassigned > 0 {
+        println!("warning: {} note(s) had no slot to play them", unassigned);
+    }
+
+    match write_rendering(output, &packets) {
+        Ok(_) => println!("baked {} packet(s) for {} slot(s) to {}", packets.len(), slots, output),
+        Err(e) => println!("unable to write rendering {}: {}", output, e),
+    }
+}
+
+fn slot_of(clients: &[ClientUID], uid: ClientUID) -> usize {
+    clients.iter()
+        .position(|client| *client == uid)
+        .expect("policy selected a client that doesn't exist")
+}
+
+fn write_rendering(path: &str, packets: &[RenderedPacket]) -> Result<(), String> {
+    let file = File::create(path)
+        .map_err(|e| e.to_string())?;
+    let mut writer = BufWriter::new(file);
+
+    for packet in packets {
+        let line = serde_json::to_string(packet)
+            .expect("failed to serialise rendered packet");
+        writeln!(writer, "{}", line)
+            .map_err(|e| e.to_string())?;
+    }
+
+    writer.flush()
+        .map_err(|e| e.to_string())
+}
+
+/// Reads a rendering made by `bake`, which may have been edited since. Blank lines and lines
+/// starting with `#` are ignored.
+pub fn load(path: &Path) -> Result<Rendering, String> {
+    let file = File::open(path)
+        .map_err(|e| format!("unable to open rendering {}: {}", path.display(), e))?;
+
+    let mut packets = Vec::new();
+    for (index, line) in BufReader::new(file).lines().enumerate() {
+        let line = line
+            .map_err(|e| format!("unable to read rendering {}: {}", path.display(), e))?;
+        let line = line.trim();
+
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+
+        let packet: RenderedPacket = serde_json::from_str(line)
+            .map_err(|e| format!("invalid packet on line {} of {}: {}", index + 1, path.display(), e))?;
+        packets.push(packet);
+    }
+
+    // hand edits needn't keep the lines in order
+    packets.sort_by_key(|packet| packet.at);
+
+    let slots = packets.iter()
+        .map(|packet| packet.slot + 1)
+        .max()
+        .unwrap_or(0);
+
+    Ok(Rendering {
+        name: path.file_stem()
+            .map(|stem| stem.to_string_lossy().into_owned())
+            .unwrap_or_else(|| path.display().to_string()),
+        slots,
+        packets,
+    })
+}
+
+/// Moves every timestamp in a rendered packet on by the given number of nanoseconds, e.g. to the
+/// server's clock at the moment the rendering starts playing.
+pub fn shift_times(packet: Packet, by: u64) -> Packet {
+    match packet {
+        Packet::PlayNote { start_time, duration, frequency, volume, channel } => Packet::PlayNote {
+            start_time: start_time + by,
+            duration,
+            frequency,
+            volume,
+            channel,
+        },
+        Packet::NoteOn { id, start_time, frequency, volume, channel } => Packet::NoteOn {
+            id,
+            start_time: start_time + by,
+            frequency,
+            volume,
+            channel,
+        },
+        Packet::NoteOff { id, end_time } => Packet::NoteOff {
+            id,
+            end_time: end_time + by,
+        },
+        Packet::NoteBatch { start_time, notes } => Packet::NoteBatch {
+            start_time: start_time + by,
+            notes,
+        },
+        Packet::PitchBend { start_time, channel, semitones } => Packet::PitchBend {
+            start_time: start_time + by,
+            channel,
+            semitones,
+        },
+        packet => packet,
+    }
+}
+
+/// When the last sound a rendered packet makes ends, in nanoseconds after the rendering starts.
+pub fn end_offset(packet: &Packet) -> u64 {
+    match *packet {
+        Packet::PlayNote { start_time, duration, .. } => start_time + duration,
+        Packet::NoteOn { start_time, .. } => start_time,
+        Packet::PitchBend { start_time, .. } => start_time,
+        Packet::NoteOff { end_time, .. } => end_time,
+        Packet::NoteBatch { start_time, ref notes } => notes.iter()
+            .fold(start_time, |latest, note| max(latest, start_time + note.start_offset + note.duration)),
+        _ => 0,
+    }
+}
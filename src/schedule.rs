@@ -0,0 +1,78 @@
+use std::collections::BinaryHeap;
+use std::cmp::Ordering;
+use std::time::Instant;
+
+struct Entry<T> {
+    at: Instant,
+    sequence: u64,
+    item: T,
+}
+
+impl<T> PartialEq for Entry<T> {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl<T> Eq for Entry<T> {}
+
+impl<T> PartialOrd for Entry<T> {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl<T> Ord for Entry<T> {
+    // reversed so that the BinaryHeap (a max-heap) yields the earliest entry first, with entries
+    // due at the same instant coming out in the order they were pushed
+    fn cmp(&self, other: &Self) -> Ordering {
+        other.at.cmp(&self.at)
+            .then_with(|| other.sequence.cmp(&self.sequence))
+    }
+}
+
+/// A queue of items that each become due at a particular instant.
+pub struct Schedule<T> {
+    heap: BinaryHeap<Entry<T>>,
+    next_sequence: u64,
+}
+
+impl<T> Schedule<T> {
+    pub fn new() -> Self {
+        Self {
+            heap: BinaryHeap::new(),
+            next_sequence: 0,
+        }
+    }
+
+    pub fn push(&mut self, at: Instant, item: T) {
+        let sequence = self.next_sequence;
+        self.next_sequence += 1;
+
+        self.heap.push(Entry {
+            at,
+            sequence,
+            item,
+        });
+    }
+
+    pub fn next_due(&self) -> Option<Instant> {
+        self.heap.peek()
+            .map(|entry| entry.at)
+    }
+
+    pub fn pop_due(&mut self, now: Instant) -> Option<T> {
+        self.pop_due_at(now)
+            .map(|(_, item)| item)
+    }
+
+    /// Like `pop_due`, but also says when the item was due.
+    pub fn pop_due_at(&mut self, now: Instant) -> Option<(Instant, T)> {
+        if self.next_due().map(|at| at <= now).unwrap_or(false) {
+            self.heap.pop()
+                .map(|entry| (entry.at, entry.item))
+        } else {
+            None
+        }
+    }
+}
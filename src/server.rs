@@ -1,91 +1,1460 @@
-use connection::{Connection, ClientUIDFactory, ClientInfo};
-use policies::{select_policy, ClientSelectionPolicy};
-use midi::{MusicalEvent, Note, TimingChange};
+use connection::{Connection, ClientUID, ClientUIDFactory, ClientInfo, Codec, SendError, FrameError};
+use policies::{select_policy, client_for_slot, ClientSelectionPolicy, BySlotPolicy, Assignment};
+use midi::{MusicalEvent, Note, PitchBend, Position, Metadata, MidiLoadError};
 use convert_duration::*;
-use packet::Packet;
+use packet::{Packet, BatchedNote, Waveform, PROTOCOL_VERSION};
+use multicast::{MulticastSender, MulticastError};
+use recording::Recorder;
+use rendering::{self, Rendering};
+use control::{self, Command};
+use playlist::{self, Playlist};
+use status;
+use latency::RoundTripEstimate;
+use schedule::Schedule;
+use clock::Clock;
 use midi;
 
 use std::time::{Duration, Instant};
 use std::thread::{sleep, spawn};
-use std::collections::HashSet;
+use std::collections::{HashSet, HashMap, BTreeMap};
 use std::io::{Stdout, Write};
 use std::sync::{Arc, Mutex};
-use std::net::TcpListener;
+use std::sync::mpsc::{channel, Receiver};
+use std::net::{TcpListener, TcpStream, Shutdown, SocketAddrV4};
 use std::str::FromStr;
 use std::hash::Hash;
 use std::fmt::Debug;
+use std::process;
+use std::path::{Path, PathBuf};
+use std::cmp::{min, max};
 use std;
 
 use itertools::Itertools;
 use pbr::ProgressBar;
 use pitch_calc::Step;
 use clap::ArgMatches;
+use serde_json;
 use term_size;
 
+/// Pings double as heartbeats: a client that hasn't answered one for `HEARTBEAT_TIMEOUT` is
+/// presumed dead and evicted.
+const HEARTBEAT_INTERVAL: Duration = Duration::from_millis(500);
+const HEARTBEAT_TIMEOUT: Duration = Duration::from_secs(3);
+
+/// How long to wait for clients to connect when not told to wait for anything in particular.
+const DEFAULT_GATHERING_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// How often the list of connected clients is checked while waiting for them.
+const GATHERING_POLL_INTERVAL: Duration = Duration::from_millis(250);
+
+/// How long a newly connected client has to identify itself and finish synchronising its clock.
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// What to do when a client's outbound queue fills up because it can't keep up.
+#[derive(Copy, Clone, Debug)]
+enum OverflowPolicy {
+    /// Drop the notes that don't fit, they'd be late anyway.
+    Drop,
+    /// Stop assigning notes to the client until it has caught up.
+    Reassign,
+}
+
+#[derive(Clone)]
+struct ConnectionSettings {
+    codec: Codec,
+    queue_size: usize,
+    /// Where set, timed packets go to this group rather than over each client's connection.
+    multicast: Option<SocketAddrV4>,
+    recorder: Option<Recorder>,
+}
+
+/// Which tracks and channels of each song are played, and how they're changed beforehand.
+pub struct SongOptions {
+    /// Track numbers or names, which are only known once each song is loaded.
+    included_tracks: HashSet<String>,
+    excluded_tracks: HashSet<String>,
+    included_channels: HashSet<u8>,
+    excluded_channels: HashSet<u8>,
+    allow_channel_10: bool,
+    /// Multiplies the speed of playback.
+    tempo: f64,
+    /// Semitones to shift every note by.
+    transpose: i32,
+    start_at: Option<Position>,
+    end_at: Option<Position>,
+}
+
+/// What to wait for before playback starts, stopping early if the timeout passes.
+struct Gathering {
+    min_clients: Option<usize>,
+    names: HashSet<String>,
+    wait_for_enter: bool,
+    timeout: Option<Duration>,
+}
+
+impl Gathering {
+    fn is_unconditional(&self) -> bool {
+        self.min_clients.is_none() && self.names.is_empty() && self.wait_for_enter == false
+    }
+}
+
+/// How notes are sent, the same for every song.
+#[derive(Copy, Clone)]
+struct PlaybackSettings {
+    lead: Duration,
+    lookahead: Option<Duration>,
+    stream_notes: bool,
+    volume_coefficient: f32,
+    /// Whether to repeat each song's region until told to move on.
+    looping: bool,
+}
+
+/// A song ready to play, and the part of it that's to be played.
+pub struct Song {
+    pub name: String,
+    pub events: Vec<MusicalEvent>,
+    pub start: Duration,
+    pub end: Duration,
+}
+
+/// What the server has been asked to perform.
+enum Programme {
+    /// Songs loaded one at a time, each played through the policy.
+    Playlist {
+        playlist: Playlist,
+        path: PathBuf,
+        song: Song,
+    },
+    /// A rendering made by `bake`, played once as it was rendered.
+    Replay(Rendering),
+}
+
+enum SongEnd {
+    /// Every note has been sent, the last of them ending at the given instant.
+    Finished(Instant),
+    Skipped,
+    Stopped,
+}
+
+/// Where playback is up to, as far as status reports are concerned.
+#[derive(Copy, Clone)]
+enum Progress {
+    Waiting,
+    /// Playing, as if from the very start of the song at the given instant.
+    Playing(Instant),
+    Paused(Duration),
+    Finished,
+}
+
+#[derive(Serialize)]
+struct Status {
+    song: Option<String>,
+    state: &'static str,
+    /// Seconds into the song.
+    position: Option<f64>,
+    policy: String,
+    clients: Vec<ClientStatus>,
+    assignments: Vec<Assignment>,
+}
+
+#[derive(Serialize)]
+struct ClientStatus {
+    uid: ClientUID,
+    name: String,
+    address: Option<String>,
+    latency_ms: f64,
+    lagging: bool,
+    queued: usize,
+}
+
+/// A note that has been assigned and hasn't finished yet, kept so that clients that take over its
+/// part can be sent whatever remains of it.
+struct ActiveNote {
+    note: Note,
+    start_time: u64,
+    end_time: u64,
+    frequency: f32,
+    volume: f32,
+    /// The clients playing the note, which carry on with it even when their parts change.
+    owners: Vec<ClientUID>,
+}
+
 struct SharedState {
     connections: Vec<Connection>,
+    active_notes: Vec<ActiveNote>,
+    clock: Clock,
+    round_trips: HashMap<ClientUID, RoundTripEstimate>,
+    last_heard: HashMap<ClientUID, Instant>,
+    lagging: HashSet<ClientUID>,
+    dropped_notes: HashMap<ClientUID, usize>,
+    /// The last bend sent for each channel, so that clients joining part way through a song are
+    /// bent like everyone else.
+    bends: BTreeMap<u8, Packet>,
+    /// `NoteOff`s that didn't fit in a client's queue, with when they were meant to be sent. These
+    /// are never dropped, as the client would otherwise hold the note until silenced.
+    held_note_offs: HashMap<ClientUID, Vec<(Packet, u64)>>,
+    overflow: OverflowPolicy,
     progress_bar: ProgressBar<Stdout>,
     width: usize,
     policy: Box<ClientSelectionPolicy>,
+    /// What's playing and where it's up to, for status reports.
+    song: Option<String>,
+    progress: Progress,
+}
+
+impl SharedState {
+    fn new(music_length: u64, policy: Box<ClientSelectionPolicy>, overflow: OverflowPolicy, clock: Clock) -> Self {
+        let width = match term_size::dimensions() {
+            Some((w, _)) => w,
+            _ => 80,
+        };
+
+        Self {
+            connections: Vec::new(),
+            active_notes: Vec::new(),
+            clock,
+            round_trips: HashMap::new(),
+            last_heard: HashMap::new(),
+            lagging: HashSet::new(),
+            dropped_notes: HashMap::new(),
+            held_note_offs: HashMap::new(),
+            bends: BTreeMap::new(),
+            overflow,
+            progress_bar: new_progress_bar(music_length),
+            width,
+            policy,
+            song: None,
+            progress: Progress::Waiting,
+        }
+    }
+
+    /// Moves on to a new song, recomputing every client's part with the song's policy.
+    fn start_song(&mut self, policy: Box<ClientSelectionPolicy>, music_length: u64) {
+        self.policy = policy;
+        self.progress_bar = new_progress_bar(music_length);
+        self.active_notes.clear();
+        self.reassign();
+    }
+
+    fn print_before(&self, text: &str) {
+        println!("\r{}\r{}", std::iter::repeat(" ").take(self.width).collect::<String>(), text);
+    }
+
+    fn add_client(&mut self, connection: Connection) {
+        for bend in self.bends.values() {
+            // failures here are picked up by the next note or heartbeat sent
+            connection.send(bend.clone()).ok();
+        }
+        self.last_heard.insert(connection.info.uid, Instant::now());
+        self.connections.push(connection);
+        self.reassign();
+    }
+
+    fn evict(&mut self, uid: ClientUID, reason: &str) {
+        let index = match self.connections.iter().position(|c| c.info.uid == uid) {
+            Some(index) => index,
+            None => return,
+        };
+
+        let connection = self.connections.remove(index);
+        connection.stream.shutdown(Shutdown::Both).ok();
+        self.round_trips.remove(&uid);
+        self.last_heard.remove(&uid);
+        self.lagging.remove(&uid);
+        self.held_note_offs.remove(&uid);
+
+        self.print_before(&format!(
+            "warning: evicted {:?} \"{}\" ({}), {} client(s) remain",
+            uid,
+            connection.info.name,
+            reason,
+            self.connections.len()
+        ));
+        self.reassign();
+    }
+
+    fn reassign(&mut self) {
+        let clients_info = self.connections.iter()
+            .filter(|c| self.lagging.contains(&c.info.uid) == false)
+            .map(|c| c.info.clone())
+            .collect::<Vec<_>>();
+        self.policy.on_clients_changed(&clients_info);
+
+        let assignments = self.policy.assignments();
+        if assignments.len() > 0 {
+            let mut text = "assignments:".to_string();
+            for assignment in assignments {
+                let clients = assignment.clients.iter()
+                    .map(|uid| format!("{:?}", uid))
+                    .join(", ");
+                text += &format!("\n  {} => {}", assignment.part, clients);
+            }
+            self.print_before(&text);
+        }
+
+        self.hand_over_active_notes();
+    }
+
+    /// Stops every client playing anything, forgetting the notes they were in the middle of.
+    fn silence(&mut self) {
+        self.active_notes.clear();
+        self.held_note_offs.clear();
+        // clients straighten every bend when silenced
+        self.bends.clear();
+        for connection in self.connections.iter() {
+            // a client that can't be told will be found out by the heartbeat soon enough
+            connection.send(Packet::Silence).ok();
+        }
+    }
+
+    fn add_active_note(&mut self, active_note: ActiveNote) {
+        let now = self.clock.now();
+        self.active_notes.retain(|active| active.end_time > now);
+        self.active_notes.push(active_note);
+    }
+
+    /// Sends the remainder of every unfinished note whose clients have been evicted or have fallen
+    /// behind, or that no client was playing, to clients now responsible for it, so that a long note isn't lost when its part
+    /// changes hands. Notes whose clients are still playing them aren't handed over, as they'd
+    /// then be heard twice.
+    fn hand_over_active_notes(&mut self) {
+        let now = self.clock.now();
+        self.active_notes.retain(|active| active.end_time > now);
+
+        let SharedState { ref mut active_notes, ref connections, ref policy, ref lagging, .. } = *self;
+        for active in active_notes.iter_mut() {
+            let (still_playing, departed): (Vec<ClientUID>, Vec<ClientUID>) = active.owners.iter()
+                .partition(|uid| lagging.contains(uid) == false && connections.iter().any(|c| c.info.uid == **uid));
+            if still_playing.len() > 0 && departed.is_empty() {
+                continue;
+            }
+
+            // each client that stopped playing the note is replaced by at most one that takes it up,
+            // though a note nobody is playing, e.g. one that started before any client joined,
+            // goes to everyone now responsible for it
+            let wanted = if still_playing.is_empty() { usize::max_value() } else { departed.len() };
+            let takers = policy.select_clients(&active.note)
+                .into_iter()
+                .filter(|uid| still_playing.contains(uid) == false)
+                .take(wanted)
+                .collect::<Vec<_>>();
+
+            for uid in takers.iter() {
+                let start_time = max(active.start_time, now);
+                let packet = Packet::PlayNote {
+                    start_time,
+                    duration: active.end_time - start_time,
+                    frequency: active.frequency,
+                    volume: active.volume,
+                    channel: active.note.channel,
+                };
+
+                if let Some(connection) = connections.iter().find(|c| c.info.uid == *uid) {
+                    // failures here are picked up by the next note or heartbeat sent
+                    connection.send(packet).ok();
+                }
+            }
+
+            active.owners = still_playing.into_iter()
+                .chain(takers)
+                .collect();
+        }
+    }
+
+    fn overflowed(&mut self, uid: ClientUID) {
+        match self.overflow {
+            OverflowPolicy::Drop => {
+                let dropped = {
+                    let dropped = self.dropped_notes.entry(uid).or_insert(0);
+                    *dropped += 1;
+                    *dropped
+                };
+
+                if dropped == 1 {
+                    self.print_before(&format!("warning: {:?} isn't keeping up, dropping its late notes", uid));
+                }
+            },
+            OverflowPolicy::Reassign => {
+                if self.lagging.insert(uid) {
+                    self.print_before(&format!("warning: {:?} isn't keeping up, reassigning its parts", uid));
+                    self.reassign();
+                }
+            },
+        }
+    }
+
+    fn hold_note_off(&mut self, uid: ClientUID, packet: Packet, scheduled: u64) {
+        self.held_note_offs.entry(uid)
+            .or_insert_with(Vec::new)
+            .push((packet, scheduled));
+    }
+
+    /// Sends as many held `NoteOff`s as each client's queue has room for, in the order they fell due.
+    fn send_held_note_offs(&mut self) {
+        let mut failed = Vec::new();
+
+        for (uid, held) in self.held_note_offs.iter_mut() {
+            let connection = match self.connections.iter().find(|c| c.info.uid == *uid) {
+                Some(connection) => connection,
+                None => continue,
+            };
+
+            let mut sent = 0;
+            for &(ref packet, scheduled) in held.iter() {
+                match connection.send_scheduled(packet.clone(), scheduled) {
+                    Ok(_) => sent += 1,
+                    Err(SendError::QueueFull) => break,
+                    Err(SendError::Failed(reason)) => {
+                        failed.push((*uid, reason));
+                        break;
+                    },
+                }
+            }
+            held.drain(..sent);
+        }
+        self.held_note_offs.retain(|_, held| held.len() > 0);
+
+        for (uid, reason) in failed {
+            self.evict(uid, &format!("failed to send note: {}", reason));
+        }
+    }
+
+    fn restore_caught_up_clients(&mut self) {
+        let caught_up = self.connections.iter()
+            .filter(|c| self.lagging.contains(&c.info.uid) && c.queued() == 0)
+            .map(|c| c.info.uid)
+            .collect::<Vec<_>>();
+
+        if caught_up.len() > 0 {
+            for uid in caught_up {
+                self.lagging.remove(&uid);
+                self.print_before(&format!("{:?} has caught up, assigning it parts again", uid));
+            }
+            self.reassign();
+        }
+    }
+
+    fn heard_from(&mut self, uid: ClientUID) {
+        if let Some(last_heard) = self.last_heard.get_mut(&uid) {
+            *last_heard = Instant::now();
+        }
+    }
+
+    fn record_round_trip(&mut self, uid: ClientUID, round_trip: u64) {
+        self.round_trips.entry(uid)
+            .or_insert_with(RoundTripEstimate::new)
+            .record(round_trip);
+    }
+
+    fn one_way_latency(&self, uid: ClientUID) -> Duration {
+        self.round_trips.get(&uid)
+            .map(|estimate| estimate.one_way())
+            .unwrap_or(Duration::new(0, 0))
+    }
+
+    fn status(&self, policy_name: &str) -> Status {
+        let (state, position) = match self.progress {
+            Progress::Waiting => ("waiting", None),
+            Progress::Playing(zero) => {
+                let now = Instant::now();
+                let position = if now > zero { now - zero } else { Duration::new(0, 0) };
+                ("playing", Some(position))
+            },
+            Progress::Paused(position) => ("paused", Some(position)),
+            Progress::Finished => ("finished", None),
+        };
+
+        let clients = self.connections.iter()
+            .map(|c| ClientStatus {
+                uid: c.info.uid,
+                name: c.info.name.clone(),
+                address: c.stream.peer_addr().ok().map(|address| address.to_string()),
+                latency_ms: duration_to_seconds(self.one_way_latency(c.info.uid)) * 1000.0,
+                lagging: self.lagging.contains(&c.info.uid),
+                queued: c.queued(),
+            })
+            .collect();
+
+        Status {
+            song: self.song.clone(),
+            state,
+            position: position.map(duration_to_seconds),
+            policy: policy_name.to_string(),
+            clients,
+            assignments: self.policy.assignments(),
+        }
+    }
+
+    /// The client playing a slot of a rendering, leaving out lagging clients as `reassign` does.
+    fn slot_client(&self, slot: usize) -> Option<ClientUID> {
+        let clients = self.connections.iter()
+            .map(|c| c.info.uid)
+            .filter(|uid| self.lagging.contains(uid) == false)
+            .collect::<Vec<_>>();
+        client_for_slot(&clients, slot)
+    }
+
+    fn max_one_way_latency(&self) -> Duration {
+        self.connections.iter()
+            .map(|c| self.one_way_latency(c.info.uid))
+            .max()
+            .unwrap_or(Duration::new(0, 0))
+    }
+}
+
+fn new_progress_bar(music_length: u64) -> ProgressBar<Stdout> {
+    let mut progress_bar = ProgressBar::new(music_length);
+    progress_bar.format("╢▌▌░╟");
+    progress_bar
+}
+
+pub fn server(matches: &ArgMatches) {
+    let paths = matches.values_of("midi")
+        .map(|paths| paths.collect::<Vec<_>>())
+        .unwrap_or_else(Vec::new);
+    let port: u16 = match matches.value_of("port").unwrap().parse() {
+        Ok(value) => value,
+        Err(_) => {
+            println!("invalid port value, must be integer between 0-65535 inclusive");
+            return;
+        },
+    };
+    let verbose = matches.is_present("verbose");
+    let stream_notes = matches.is_present("stream notes");
+    let codec = Codec::new(matches.is_present("checksums"));
+    let multicast = match matches.value_of("multicast").map(|value| value.parse::<SocketAddrV4>()) {
+        Some(Ok(group)) if group.ip().is_multicast() => Some(group),
+        Some(_) => {
+            println!("invalid multicast group, must be a multicast IPv4 address and port such as 239.255.77.77:4001");
+            return;
+        },
+        None => None,
+    };
+    let lookahead = match matches.value_of("lookahead").map(|value| value.parse::<u64>()) {
+        Some(Ok(0)) | Some(Err(_)) => {
+            println!("invalid lookahead value, must be a whole number of milliseconds above zero");
+            return;
+        },
+        Some(Ok(value)) => Some(Duration::from_millis(value)),
+        None => None,
+    };
+    let volume_coefficient: f32 = match matches.value_of("volume").unwrap().parse() {
+        Ok(value) => value,
+        Err(_) => {
+            println!("invalid volume value, must be floating point number");
+            return;
+        },
+    };
+    let policy_name = matches.value_of("policy").unwrap();
+    let lead: u64 = match matches.value_of("lead").unwrap().parse() {
+        Ok(value) => value,
+        Err(_) => {
+            println!("invalid lead value, must be a whole number of milliseconds");
+            return;
+        },
+    };
+    let lead = Duration::from_millis(lead);
+    let queue_size: usize = match matches.value_of("queue size").unwrap().parse() {
+        Ok(value) if value > 0 => value,
+        _ => {
+            println!("invalid queue size, must be a whole number above zero");
+            return;
+        },
+    };
+    let overflow = match matches.value_of("overflow").unwrap() {
+        "reassign" => OverflowPolicy::Reassign,
+        _ => OverflowPolicy::Drop,
+    };
+    let min_clients = match matches.value_of("wait for clients").map(|value| value.parse::<usize>()) {
+        Some(Ok(value)) => Some(value),
+        Some(Err(_)) => {
+            println!("invalid client count, must be a whole number");
+            return;
+        },
+        None => None,
+    };
+    let gathering_timeout = match matches.value_of("wait timeout").map(|value| value.parse::<f64>()) {
+        Some(Ok(value)) if value >= 0.0 => Some(seconds_to_duration(value)),
+        Some(_) => {
+            println!("invalid wait timeout, must be a number of seconds");
+            return;
+        },
+        None => None,
+    };
+    let mut gathering = Gathering {
+        min_clients,
+        names: matches.values_of("wait for names")
+            .map(|names| names.map(|name| name.to_string()).collect())
+            .unwrap_or_else(HashSet::new),
+        wait_for_enter: matches.is_present("wait for enter"),
+        timeout: gathering_timeout,
+    };
+    if gathering.is_unconditional() && gathering.timeout.is_none() {
+        gathering.timeout = Some(DEFAULT_GATHERING_TIMEOUT);
+    }
+    let record_path = matches.value_of("record");
+    let status_port = match matches.value_of("status port").map(|value| value.parse::<u16>()) {
+        Some(Ok(value)) => Some(value),
+        Some(Err(_)) => {
+            println!("invalid status port value, must be integer between 0-65535 inclusive");
+            return;
+        },
+        None => None,
+    };
+    let control_port = match matches.value_of("control port").map(|value| value.parse::<u16>()) {
+        Some(Ok(value)) => Some(value),
+        Some(Err(_)) => {
+            println!("invalid control port value, must be integer between 0-65535 inclusive");
+            return;
+        },
+        None => None,
+    };
+
+    if volume_coefficient < 0.0 || volume_coefficient > 1.0 {
+        println!("invalid volume value, must be between 0.0 and 1.0");
+        return;
+    }
+
+    let song_options = match parse_song_options(matches) {
+        Ok(options) => options,
+        Err(e) => {
+            println!("{}", e);
+            return;
+        },
+    };
+    let playback_settings = PlaybackSettings {
+        lead,
+        lookahead,
+        stream_notes,
+        volume_coefficient,
+        looping: matches.is_present("loop"),
+    };
+
+    let mut programme = match matches.value_of("replay") {
+        Some(path) => match rendering::load(Path::new(path)) {
+            Ok(rendering) => {
+                println!("replaying {} packet(s) for {} slot(s)", rendering.packets.len(), rendering.slots);
+                Programme::Replay(rendering)
+            },
+            Err(e) => {
+                println!("{}", e);
+                return;
+            },
+        },
+        None => {
+            let songs = match playlist::expand_paths(&paths) {
+                Ok(songs) => songs,
+                Err(e) => {
+                    println!("{}", e);
+                    return;
+                },
+            };
+            if songs.is_empty() {
+                println!("no songs to play");
+                return;
+            }
+            let mut playlist = Playlist::new(songs, matches.is_present("shuffle"), matches.is_present("repeat"));
+            println!("playlist of {} song(s)", playlist.len());
+
+            let (path, song) = match load_next_song(&mut playlist, &song_options, verbose) {
+                Ok(Some(next)) => next,
+                Ok(None) => {
+                    println!("no songs to play");
+                    process::exit(1);
+                },
+                Err(e) => {
+                    println!("{}", e);
+                    process::exit(1);
+                },
+            };
+
+            Programme::Playlist { playlist, path, song }
+        },
+    };
+
+    let listener = TcpListener::bind(format!("0.0.0.0:{}", port))
+        .expect("unable to create TCP server");
+
+    let (policy, music_length) = match programme {
+        Programme::Playlist { ref song, .. } => {
+            let policy = select_policy(policy_name.to_string(), &song.events)
+                .expect("invalid policy");
+            println!("client selection policy: {}", policy_name);
+            (policy, song.events.len() as u64)
+        },
+        Programme::Replay(ref rendering) => {
+            let policy: Box<ClientSelectionPolicy> = Box::new(BySlotPolicy::new(rendering.slots));
+            (policy, rendering.packets.len() as u64)
+        },
+    };
+    // status reports name the policy, which while replaying was whatever the rendering was baked with
+    let policy_name = match programme {
+        Programme::Playlist { .. } => policy_name,
+        Programme::Replay(_) => "replay",
+    };
+
+    let clock = Clock::new();
+    let shared_state_original = Arc::new(Mutex::new(
+        SharedState::new(music_length, policy, overflow, clock)
+    ));
+
+    let recorder = match record_path {
+        Some(path) => match Recorder::create(path, clock) {
+            Ok(recorder) => {
+                println!("recording every packet sent to {}", path);
+                Some(recorder)
+            },
+            Err(e) => {
+                println!("unable to open recording {}: {}", path, e);
+                return;
+            },
+        },
+        None => None,
+    };
+
+    let settings = ConnectionSettings {
+        codec,
+        queue_size,
+        multicast,
+        recorder: recorder.clone(),
+    };
+
+    let multicast_sender = match multicast {
+        Some(group) => {
+            println!("multicasting notes to {}", group);
+            Some(MulticastSender::new(group, codec)
+                .expect("unable to create multicast socket"))
+        },
+        None => None,
+    };
+
+    let shared_state = shared_state_original.clone();
+    spawn(move || {
+        println!("accepting client connections...");
+        let mut client_uid_factory = ClientUIDFactory::new();
+        for stream in listener.incoming() {
+            match stream {
+                Ok(s) => {
+                    let uid = client_uid_factory.make();
+                    let shared_state = shared_state_original.clone();
+                    let settings = settings.clone();
+
+                    // handshakes happen on their own thread, without the shared state locked, so
+                    // that a client that connects and then says nothing can't hold anyone else up
+                    spawn(move || {
+                        let result = handshake(s, uid, &clock, &settings);
+
+                        let mut state = shared_state.lock()
+                            .expect("failed to acquire mutex while accepting");
+
+                        match result {
+                            Ok(connection) => {
+                                state.print_before(&format!(
+                                    "connection accepted: {:?} \"{}\" ({:?})",
+                                    connection.info.uid,
+                                    connection.info.name,
+                                    connection.info.capabilities
+                                ));
+                                read_client_packets(&connection, shared_state.clone(), clock, codec);
+                                state.add_client(connection);
+                            },
+                            Err(reason) => {
+                                state.print_before(&format!("connection rejected: {}", reason));
+                            },
+                        }
+                    });
+                },
+                Err(e) => panic!("IO error while listening: {}", e),
+            }
+        }
+    });
+
+    let shared_state_for_heartbeats = shared_state.clone();
+    spawn(move || {
+        loop {
+            sleep(HEARTBEAT_INTERVAL);
+
+            let mut state = shared_state_for_heartbeats.lock()
+                .expect("failed to acquire mutex to send heartbeats");
+
+            let now = Instant::now();
+            let mut dead = Vec::new();
+            for connection in state.connections.iter() {
+                let uid = connection.info.uid;
+                let silent_for = state.last_heard.get(&uid)
+                    .map(|last_heard| now - *last_heard)
+                    .unwrap_or(Duration::new(0, 0));
+
+                if silent_for > HEARTBEAT_TIMEOUT {
+                    dead.push((uid, format!("no heartbeat for {}ms", (duration_to_seconds(silent_for) * 1000f64) as u64)));
+                } else if let Err(SendError::Failed(reason)) = connection.send(Packet::Ping { server_time: clock.now() }) {
+                    dead.push((uid, format!("failed to send heartbeat: {}", reason)));
+                }
+            }
+
+            for (uid, reason) in dead {
+                state.evict(uid, &reason);
+            }
+
+            // between songs nothing else would send them
+            state.send_held_note_offs();
+            state.restore_caught_up_clients();
+        }
+    });
+
+    if let Some(status_port) = status_port {
+        let shared_state = shared_state.clone();
+        let policy_name = policy_name.to_string();
+        let result = status::serve(status_port, move || {
+            let state = shared_state.lock()
+                .expect("failed to acquire mutex to report status");
+            serde_json::to_string(&state.status(&policy_name))
+                .expect("failed to serialise status")
+        });
+
+        match result {
+            Ok(_) => println!("serving status at http://127.0.0.1:{}/status", status_port),
+            Err(e) => println!("warning: unable to serve status on port {}: {}", status_port, e),
+        }
+    }
+
+    let (commands, command_receiver) = channel();
+    control::listen_stdin(commands.clone());
+    if let Some(control_port) = control_port {
+        match control::listen_socket(control_port, commands.clone()) {
+            Ok(_) => println!("accepting control commands on 127.0.0.1:{}", control_port),
+            Err(e) => println!("warning: unable to listen for control commands on port {}: {}", control_port, e),
+        }
+    }
+    println!("type pause, resume, seek <seconds|bar:beat>, skip or stop to control playback");
+
+    let mut next_note_id: u32 = 0;
+    let mut load_error = None;
+    let mut latest_note_end_time = Instant::now();
+    let mut playing = gather_clients(&shared_state, &gathering, &command_receiver);
+    while playing {
+        let end = match programme {
+            Programme::Playlist { ref path, ref song, .. } => {
+                println!("starting playback of {}!", path.display());
+                play_song(song, &shared_state, clock, &multicast_sender, &recorder, &command_receiver, &playback_settings, &mut next_note_id)
+            },
+            Programme::Replay(ref rendering) => {
+                println!("starting replay of {}!", rendering.name);
+                replay(rendering, &shared_state, clock, &multicast_sender, &recorder, &command_receiver, playback_settings.lead)
+            },
+        };
+
+        match end {
+            SongEnd::Finished(end_time) => latest_note_end_time = end_time,
+            SongEnd::Skipped => latest_note_end_time = Instant::now(),
+            SongEnd::Stopped => {
+                latest_note_end_time = Instant::now();
+                playing = false;
+                continue;
+            },
+        }
+
+        let (music_length, policy) = match programme {
+            Programme::Playlist { ref mut playlist, ref mut path, ref mut song } => {
+                let (next_path, next_song) = match load_next_song(playlist, &song_options, verbose) {
+                    Ok(Some(next)) => next,
+                    Ok(None) => {
+                        playing = false;
+                        continue;
+                    },
+                    Err(e) => {
+                        // the clients are still told to finish up before the failure is reported
+                        load_error = Some(e);
+                        playing = false;
+                        continue;
+                    },
+                };
+                *path = next_path;
+                *song = next_song;
+                let policy = select_policy(policy_name.to_string(), &song.events)
+                    .expect("invalid policy");
+                (song.events.len() as u64, policy)
+            },
+            Programme::Replay(_) => {
+                playing = false;
+                continue;
+            },
+        };
+
+        // let the last song ring out before the next begins
+        let now = Instant::now();
+        if now < latest_note_end_time {
+            sleep(latest_note_end_time - now);
+        }
+
+        let mut state = shared_state.lock()
+            .expect("failed to lock mutex to change song");
+        state.start_song(policy, music_length);
+    }
+
+    let mut state = shared_state.lock()
+        .expect("failed to lock mutex for shutdown processes");
+
+    let now = Instant::now();
+    let terminate_delay = if now < latest_note_end_time {
+        duration_to_nanoseconds(latest_note_end_time - now)
+    } else {
+        0
+    };
+
+    // taking the connections out of the shared state stops the heartbeat and reader threads from
+    // treating the shutdown as clients dying
+    let connections = state.connections.drain(..).collect::<Vec<_>>();
+
+    for (uid, dropped) in state.dropped_notes.iter() {
+        println!("warning: dropped {} late note(s) for {:?}", dropped, uid);
+    }
+    drop(state);
+
+    println!("telling clients to terminate...");
+    for client in connections.iter() {
+        if let Err(e) = client.send(Packet::TerminateAfter(terminate_delay)) {
+            println!("warning: failed to tell {:?} to terminate: {}", client.info.uid, e);
+        }
+    }
+
+    println!("ensuring clients get termination messages...");
+    for client in connections {
+        client.close();
+    }
+
+    // better safe than sorry!
+    if terminate_delay > 0 {
+        sleep(nanoseconds_to_duration(terminate_delay));
+    }
+
+    if let Some(error) = load_error {
+        println!("{}", error);
+        process::exit(1);
+    }
+
+    println!("done");
+}
+
+/// Waits for clients to connect, listing them as they come and go, until everything the gathering
+/// waits for is in place. Returns false if told to stop instead.
+fn gather_clients(shared_state: &Arc<Mutex<SharedState>>, gathering: &Gathering, command_receiver: &Receiver<Command>) -> bool {
+    let started_waiting = Instant::now();
+    let mut entered = false;
+    let mut listed = None;
+
+    if let Some(timeout) = gathering.timeout {
+        println!("waiting up to {} seconds for clients to connect...", duration_to_seconds(timeout));
+    } else {
+        println!("waiting for clients to connect...");
+    }
+    if gathering.wait_for_enter {
+        println!("press enter (or send start) to begin playback");
+    }
+
+    loop {
+        {
+            let state = shared_state.lock()
+                .expect("failed to acquire mutex to list clients");
+
+            let clients = state.connections.iter()
+                .map(|c| (c.info.uid, c.info.name.clone()))
+                .collect::<Vec<_>>();
+
+            let missing_names = gathering.names.iter()
+                .filter(|name| clients.iter().any(|&(_, ref connected)| connected == *name) == false)
+                .sorted();
+
+            if listed.as_ref() != Some(&clients) {
+                let mut text = format!("{} client(s) connected", clients.len());
+                if let Some(min_clients) = gathering.min_clients {
+                    text += &format!(" (waiting for {})", min_clients);
+                }
+                for &(uid, ref name) in clients.iter() {
+                    text += &format!("\n  {:?} \"{}\"", uid, name);
+                }
+                if missing_names.len() > 0 {
+                    text += &format!("\n  still waiting for: {}", missing_names.iter().join(", "));
+                }
+                state.print_before(&text);
+                listed = Some(clients.clone());
+            }
+
+            let ready = gathering.min_clients.map(|n| clients.len() >= n).unwrap_or(true)
+                && missing_names.is_empty()
+                && (gathering.wait_for_enter == false || entered);
+            // with nothing in particular to wait for, enter still cuts the wait short
+            if ready && (gathering.is_unconditional() == false || entered) {
+                return true;
+            }
+        }
+
+        let now = Instant::now();
+        let mut wait = GATHERING_POLL_INTERVAL;
+        if let Some(timeout) = gathering.timeout {
+            let deadline = started_waiting + timeout;
+            if now >= deadline {
+                println!("finished waiting for clients");
+                return true;
+            }
+            wait = min(wait, deadline - now);
+        }
+
+        match command_receiver.recv_timeout(wait) {
+            Ok(Command::Start) => entered = true,
+            Ok(Command::Stop) => return false,
+            Ok(command) => println!("playback hasn't started yet, ignoring {:?}", command),
+            Err(_) => {},
+        }
+    }
+}
+
+/// Plays one song to the clients, returning early if told to skip it or stop.
+fn play_song(song: &Song, shared_state: &Arc<Mutex<SharedState>>, clock: Clock, multicast_sender: &Option<MulticastSender>, recorder: &Option<Recorder>, command_receiver: &Receiver<Command>, settings: &PlaybackSettings, next_note_id: &mut u32) -> SongEnd {
+    let PlaybackSettings { lead, lookahead, stream_notes, volume_coefficient, looping } = *settings;
+    let events_to_play = &song.events;
+    let first_event = first_event_from(events_to_play, song.start);
+
+    let mut latest_note_end_time = Instant::now();
+    let mut start_time = Instant::now() - song.start;
+    let mut paused_at: Option<Duration> = None;
+    let mut next_event = first_event;
+    let mut sends = Schedule::new();
+    let mut batches: HashMap<ClientUID, Vec<BatchedNote>> = HashMap::new();
+    let mut batch_window = start_time + window_start(song.start, lookahead);
+    {
+        let mut state = shared_state.lock()
+            .expect("failed to lock mutex to start song");
+        state.progress_bar.set(next_event as u64);
+        state.song = Some(song.name.clone());
+        state.progress = Progress::Playing(start_time + lead);
+        // the last song may have left channels bent
+        queue_bends_at(&mut sends, &state, events_to_play, song.start, start_time, lead, clock, multicast_sender.is_some());
+    }
+    let mut interruption = None;
+    let end = 'playback: loop {
+        // while paused there's nothing to do but wait to be told otherwise
+        let mut received = interruption.take().into_iter().collect::<Vec<_>>();
+        if paused_at.is_some() && received.is_empty() {
+            received.extend(command_receiver.recv().ok());
+        }
+        received.extend(command_receiver.try_iter());
+
+        let mut restart_from = None;
+        for command in received {
+            let mut state = shared_state.lock()
+                .expect("failed to lock mutex to handle control command");
+
+            match command {
+                Command::Start => {
+                    // already started
+                },
+                Command::Pause => {
+                    if paused_at.is_none() {
+                        // notes already sent but still within the lead haven't sounded yet
+                        let now = Instant::now();
+                        let elapsed = if now > start_time { now - start_time } else { Duration::new(0, 0) };
+                        let position = if elapsed > lead { elapsed - lead } else { Duration::new(0, 0) };
+                        paused_at = Some(position);
+                        state.progress = Progress::Paused(position);
+                        state.silence();
+                        state.print_before(&format!("paused at {:.1}s", duration_to_seconds(position)));
+                    }
+                },
+                Command::Resume => {
+                    if let Some(position) = paused_at.take() {
+                        restart_from = Some(position);
+                        state.print_before(&format!("resuming from {:.1}s", duration_to_seconds(position)));
+                    }
+                },
+                Command::Seek(position) => {
+                    let offset = midi::position_to_offset(&events_to_play, position);
+                    state.silence();
+                    state.print_before(&format!("seeking to {:.1}s", duration_to_seconds(offset)));
+                    if paused_at.is_some() {
+                        paused_at = Some(offset);
+                        state.progress = Progress::Paused(offset);
+                    } else {
+                        restart_from = Some(offset);
+                    }
+                },
+                Command::Skip => {
+                    state.silence();
+                    state.print_before("skipping to the next song");
+                    break 'playback SongEnd::Skipped;
+                },
+                Command::Stop => {
+                    state.silence();
+                    state.print_before("stopping playback");
+                    break 'playback SongEnd::Stopped;
+                },
+            }
+        }
+
+        if paused_at.is_some() {
+            continue;
+        }
+
+        if let Some(offset) = restart_from {
+            // notes that would already have started by the new position are skipped
+            start_time = Instant::now() - offset;
+            next_event = first_event_from(events_to_play, offset);
+            sends = Schedule::new();
+            batches.clear();
+            batch_window = start_time + window_start(offset, lookahead);
+            latest_note_end_time = Instant::now();
+
+            let mut state = shared_state.lock()
+                .expect("failed to lock mutex to move playback");
+            state.progress_bar.set(next_event as u64);
+            state.progress = Progress::Playing(start_time + lead);
+            // silencing straightened every bend
+            queue_bends_at(&mut sends, &state, events_to_play, offset, start_time, lead, clock, multicast_sender.is_some());
+        }
+
+        if looping && next_event >= events_to_play.len() && first_event < events_to_play.len() && song.end > song.start {
+            // carry straight on from the start of the region, as if it were written out again
+            start_time += song.end - song.start;
+            next_event = first_event;
+
+            let mut state = shared_state.lock()
+                .expect("failed to lock mutex to loop playback");
+            state.progress_bar.set(next_event as u64);
+            state.progress = Progress::Playing(start_time + lead);
+            // channels are still bent however the end of the region left them
+            queue_bends_at(&mut sends, &state, events_to_play, song.start, start_time, lead, clock, multicast_sender.is_some());
+        }
+
+        let now = Instant::now();
+        let max_latency = {
+            let state = shared_state.lock()
+                .expect("failed to acquire mutex to read latencies");
+            state.max_one_way_latency()
+        };
+
+        // each client is sent its notes early by its own latency, so queue up every event that
+        // the slowest client needs sending by now
+        while next_event < events_to_play.len() {
+            let event = &events_to_play[next_event];
+            let event_time = start_time + event.start_offset();
+            let send_point = start_time + window_start(event.start_offset(), lookahead);
+            if send_point > now + max_latency {
+                break;
+            }
+            next_event += 1;
+
+            let mut state = shared_state.lock()
+                .expect("failed to lock mutex to assign note");
+
+            if send_point != batch_window {
+                queue_batches(&mut batches, batch_window, &state, &mut sends, clock.timestamp(batch_window + lead));
+                batch_window = send_point;
+            }
+
+            match event {
+                MusicalEvent::PlayNote(note) => {
+                    let midi_note = Step(note.note as f32);
+                    let volume = (note.velocity as f32 / 128.0) * volume_coefficient;
+                    let play_time = event_time + lead;
+                    let end_time = play_time + note.duration;
+                    if end_time >= latest_note_end_time {
+                        latest_note_end_time = end_time;
+                    }
+
+                    let id = *next_note_id;
+                    *next_note_id = next_note_id.wrapping_add(1);
+
+                    let targets = state.policy.select_clients(&note);
+                    state.add_active_note(ActiveNote {
+                        note: note.clone(),
+                        start_time: clock.timestamp(play_time),
+                        end_time: clock.timestamp(end_time),
+                        frequency: midi_note.to_hz().0,
+                        volume,
+                        owners: targets.clone(),
+                    });
+
+                    if lookahead.is_some() {
+                        for uid in targets {
+                            batches.entry(uid).or_insert_with(Vec::new).push(BatchedNote {
+                                start_offset: duration_to_nanoseconds(event_time - batch_window),
+                                duration: duration_to_nanoseconds(note.duration),
+                                frequency: midi_note.to_hz().0,
+                                volume,
+                                channel: note.channel,
+                            });
+                        }
+                    } else if stream_notes {
+                        queue_send(&mut sends, &state, targets.clone(), event_time, multicast_sender.is_some(), Packet::NoteOn {
+                            id,
+                            start_time: clock.timestamp(play_time),
+                            frequency: midi_note.to_hz().0,
+                            volume,
+                            channel: note.channel,
+                        });
+                        queue_send(&mut sends, &state, targets, event_time + note.duration, multicast_sender.is_some(), Packet::NoteOff {
+                            id,
+                            end_time: clock.timestamp(end_time),
+                        });
+                    } else {
+                        queue_send(&mut sends, &state, targets, event_time, multicast_sender.is_some(), Packet::PlayNote {
+                            start_time: clock.timestamp(play_time),
+                            duration: duration_to_nanoseconds(note.duration),
+                            frequency: midi_note.to_hz().0,
+                            volume,
+                            channel: note.channel,
+                        });
+                    }
+                },
+
+                MusicalEvent::PitchBend(bend) => {
+                    // any client could be given a note on the channel before it's next bent, so
+                    // every client keeps track of every channel's bend
+                    let everyone = state.connections.iter()
+                        .map(|c| c.info.uid)
+                        .collect();
+                    queue_send(&mut sends, &state, everyone, event_time, multicast_sender.is_some(), Packet::PitchBend {
+                        start_time: clock.timestamp(event_time + lead),
+                        channel: bend.channel,
+                        semitones: bend.semitones(),
+                    });
+                },
+
+                MusicalEvent::TimingChange(_timing_change) => {
+                    // we could emit timing information here, but we won't :)
+                },
+            }
+
+            state.progress_bar.inc();
+        }
+
+        // once the next event falls in a later window, every batch for the current one is complete
+        let window_complete = events_to_play.get(next_event)
+            .map(|event| start_time + window_start(event.start_offset(), lookahead) != batch_window)
+            .unwrap_or(true);
+
+        {
+            let mut state = shared_state.lock()
+                .expect("failed to lock mutex to send notes");
+
+            if window_complete {
+                queue_batches(&mut batches, batch_window, &state, &mut sends, clock.timestamp(batch_window + lead));
+            }
+
+            send_due(&mut sends, &mut state, clock, multicast_sender, recorder);
+        }
+
+        let next_event_time = events_to_play.get(next_event)
+            .map(|event| start_time + window_start(event.start_offset(), lookahead) - max_latency);
+        let wake_time = match (next_event_time, sends.next_due()) {
+            (Some(a), Some(b)) => min(a, b),
+            (Some(a), None) => a,
+            (None, Some(b)) => b,
+            (None, None) => break SongEnd::Finished(latest_note_end_time),
+        };
+
+        let now = Instant::now();
+        if now < wake_time {
+            let time_until_note = wake_time - now;
+            {
+                let mut state = shared_state.lock()
+                    .expect("failed to acquire mutex to show sleep time");
+                state.progress_bar.message(&format!("sleep: {:04}ms: ", (duration_to_seconds(time_until_note) * 1000f64) as u64));
+            }
+            // a command cuts the sleep short, so that gaps in the music don't delay it
+            interruption = command_receiver.recv_timeout(time_until_note).ok();
+        }
+    };
+
+    let mut state = shared_state.lock()
+        .expect("failed to lock mutex to finish song");
+    state.progress_bar.finish_println("playback complete\n");
+    state.progress = Progress::Finished;
+
+    end
 }
 
-impl SharedState {
-    fn new(music_length: u64, policy: Box<ClientSelectionPolicy>) -> Self {
-        let width = match term_size::dimensions() {
-            Some((w, _)) => w,
-            _ => 80,
+/// Plays a rendering as it was baked, handing each slot's packets to whichever client is playing
+/// that slot at the time. Renderings can only be skipped or stopped, not paused or moved around.
+fn replay(rendering: &Rendering, shared_state: &Arc<Mutex<SharedState>>, clock: Clock, multicast_sender: &Option<MulticastSender>, recorder: &Option<Recorder>, command_receiver: &Receiver<Command>, lead: Duration) -> SongEnd {
+    let packets = &rendering.packets;
+    let start_time = Instant::now();
+    let start = clock.timestamp(start_time + lead);
+
+    let mut latest_note_end_time = Instant::now();
+    let mut next_packet = 0;
+    let mut sends = Schedule::new();
+    {
+        let mut state = shared_state.lock()
+            .expect("failed to lock mutex to start replay");
+        state.progress_bar.set(0);
+        state.song = Some(rendering.name.clone());
+        state.progress = Progress::Playing(start_time + lead);
+    }
+    let mut interruption = None;
+    let end = 'replay: loop {
+        let received = interruption.take().into_iter()
+            .chain(command_receiver.try_iter())
+            .collect::<Vec<_>>();
+
+        for command in received {
+            let mut state = shared_state.lock()
+                .expect("failed to lock mutex to handle control command");
+
+            match command {
+                Command::Start => {
+                    // already started
+                },
+                Command::Skip | Command::Stop => {
+                    state.silence();
+                    state.print_before("stopping replay");
+                    break 'replay SongEnd::Stopped;
+                },
+                _ => state.print_before("a rendering can't be paused or seeked, only skipped or stopped"),
+            }
+        }
+
+        let now = Instant::now();
+        let max_latency = {
+            let state = shared_state.lock()
+                .expect("failed to acquire mutex to read latencies");
+            state.max_one_way_latency()
         };
 
-        let mut progress_bar = ProgressBar::new(music_length);
-        progress_bar.format("╢▌▌░╟");
+        while next_packet < packets.len() {
+            let rendered = &packets[next_packet];
+            let due = start_time + nanoseconds_to_duration(rendered.at);
+            if due > now + max_latency {
+                break;
+            }
+            next_packet += 1;
 
-        Self {
-            connections: Vec::new(),
-            progress_bar,
-            width,
-            policy,
+            let end_time = start_time + lead + nanoseconds_to_duration(rendering::end_offset(&rendered.packet));
+            if end_time >= latest_note_end_time {
+                latest_note_end_time = end_time;
+            }
+
+            let mut state = shared_state.lock()
+                .expect("failed to lock mutex to replay packet");
+
+            // with nobody to play a slot its packets are lost, as notes are without clients
+            if let Some(uid) = state.slot_client(rendered.slot) {
+                let packet = rendering::shift_times(rendered.packet.clone(), start);
+                queue_send(&mut sends, &state, vec![uid], due, false, packet);
+            }
+
+            state.progress_bar.inc();
         }
-    }
 
-    fn print_before(&self, text: &str) {
-        println!("\r{}\r{}", std::iter::repeat(" ").take(self.width).collect::<String>(), text);
+        {
+            let mut state = shared_state.lock()
+                .expect("failed to lock mutex to send packets");
+            send_due(&mut sends, &mut state, clock, multicast_sender, recorder);
+        }
+
+        let next_packet_time = packets.get(next_packet)
+            .map(|rendered| start_time + nanoseconds_to_duration(rendered.at) - max_latency);
+        let wake_time = match (next_packet_time, sends.next_due()) {
+            (Some(a), Some(b)) => min(a, b),
+            (Some(a), None) => a,
+            (None, Some(b)) => b,
+            (None, None) => break SongEnd::Finished(latest_note_end_time),
+        };
+
+        let now = Instant::now();
+        if now < wake_time {
+            interruption = command_receiver.recv_timeout(wake_time - now).ok();
+        }
+    };
+
+    let mut state = shared_state.lock()
+        .expect("failed to lock mutex to finish replay");
+    state.progress_bar.finish_println("replay complete\n");
+    state.progress = Progress::Finished;
+
+    end
+}
+
+/// Loads the next song in the playlist that can be loaded, skipping any that can't with a warning.
+/// Gives up once every song in the playlist has failed in a row, as none of them will play.
+fn load_next_song(playlist: &mut Playlist, options: &SongOptions, verbose: bool) -> Result<Option<(PathBuf, Song)>, String> {
+    let mut failures = 0;
+
+    while failures < playlist.len() {
+        let path = match playlist.next() {
+            Some(path) => path,
+            None => return Ok(None),
+        };
+
+        match load_song(&path, options, verbose) {
+            Ok(song) => return Ok(Some((path, song))),
+            Err(e) => {
+                println!("warning: skipping {}, unable to load it: {}", path.display(), e);
+                failures += 1;
+            },
+        }
     }
+
+    Err(format!("none of the {} song(s) could be loaded", playlist.len()))
 }
 
-pub fn server(matches: &ArgMatches) {
-    let path = matches.value_of("midi").unwrap();
-    let port: u16 = match matches.value_of("port").unwrap().parse() {
-        Ok(value) => value,
-        Err(_) => {
-            println!("invalid port value, must be integer between 0-65535 inclusive");
-            return;
-        },
+/// Reads the arguments shared by every subcommand that loads songs, see `song_args`.
+pub fn parse_song_options(matches: &ArgMatches) -> Result<SongOptions, String> {
+    let tempo: f64 = match matches.value_of("tempo").unwrap().parse() {
+        Ok(value) if value > 0.0 => value,
+        _ => return Err("invalid tempo value, must be a number above zero, e.g. 0.5 for half speed".to_string()),
     };
-    let verbose = matches.is_present("verbose");
-    let volume_coefficient: f32 = match matches.value_of("volume").unwrap().parse() {
+    let start_at = match matches.value_of("start at").map(|value| value.parse::<Position>()) {
+        Some(Ok(position)) => Some(position),
+        Some(Err(e)) => return Err(format!("invalid start position: {}", e)),
+        None => None,
+    };
+    let end_at = match matches.value_of("end at").map(|value| value.parse::<Position>()) {
+        Some(Ok(position)) => Some(position),
+        Some(Err(e)) => return Err(format!("invalid end position: {}", e)),
+        None => None,
+    };
+    let transpose: i32 = match matches.value_of("transpose").unwrap().parse() {
         Ok(value) => value,
-        Err(_) => {
-            println!("invalid volume value, must be floating point number");
-            return;
-        },
+        Err(_) => return Err("invalid transpose value, must be a whole number of semitones".to_string()),
     };
-    let policy_name = matches.value_of("policy").unwrap();
 
-    if volume_coefficient < 0.0 || volume_coefficient > 1.0 {
-        println!("invalid volume value, must be between 0.0 and 1.0");
-        return;
-    }
-
-    let included_tracks = number_list_to_hashset::<usize>(matches, "include track", "track");
-    let excluded_tracks = number_list_to_hashset::<usize>(matches, "exclude track", "track");
-    let included_channels = number_list_to_hashset::<u8>(matches, "include channel", "channel");
-    let excluded_channels = number_list_to_hashset::<u8>(matches, "exclude channel", "channel");
+    Ok(SongOptions {
+        included_tracks: track_list_to_hashset(matches, "include track"),
+        excluded_tracks: track_list_to_hashset(matches, "exclude track"),
+        included_channels: number_list_to_hashset::<u8>(matches, "include channel", "channel"),
+        excluded_channels: number_list_to_hashset::<u8>(matches, "exclude channel", "channel"),
+        allow_channel_10: matches.is_present("allow channel 10"),
+        tempo,
+        transpose,
+        start_at,
+        end_at,
+    })
+}
 
-    let listener = TcpListener::bind(format!("0.0.0.0:{}", port))
-        .expect("unable to create TCP server");
+/// Loads a song, leaving out the tracks and channels that aren't to be played.
+pub fn load_song(path: &Path, options: &SongOptions, verbose: bool) -> Result<Song, MidiLoadError> {
+    println!("loading {}...", path.display());
+    let music = midi::load_midi(path, verbose)?;
+    let metadata = music.metadata();
 
-    println!("loading midi...");
-    let music = midi::load_midi(path, verbose);
+    if let Some(ref title) = metadata.title {
+        println!("title: {}", title);
+    }
+    if let Some(ref copyright) = metadata.copyright {
+        println!("copyright: {}", copyright);
+    }
+    // times are shown as they'll be played, for use with --start-at and --end-at
+    let played_at = |offset: Duration| duration_to_seconds(offset) / options.tempo;
+    for key_signature in metadata.key_signatures.iter() {
+        println!("key: {} at {:.1}s", key_signature.name(), played_at(key_signature.start_offset));
+    }
+    if metadata.markers.len() > 0 {
+        println!("markers:");
+        for marker in metadata.markers.iter() {
+            println!("  {:>7.1}s {}", played_at(marker.start_offset), marker.text);
+        }
+    }
 
     let tracks = music.events().iter()
         .filter_map(|e| {
@@ -110,8 +1479,8 @@ pub fn server(matches: &ArgMatches) {
     let tracks_before_filtering = tracks.clone();
     let channels_before_filtering = channels.clone();
 
-    let mut excluded_channels = excluded_channels;
-    if channels.contains(&10) && matches.is_present("allow channel 10") == false {
+    let mut excluded_channels = options.excluded_channels.clone();
+    if channels.contains(&10) && options.allow_channel_10 == false {
         println!("automatically ignoring channel 10");
         println!("  (set --allow-channel-10 to inhibit this)");
 
@@ -122,10 +1491,13 @@ pub fn server(matches: &ArgMatches) {
     let channels = channels.difference(&excluded_channels)
         .map(|v| *v)
         .collect::<HashSet<u8>>();
-    let channels = channels.union(&included_channels)
+    let channels = channels.union(&options.included_channels)
         .map(|v| *v)
         .collect::<HashSet<u8>>();
 
+    let excluded_tracks = resolve_tracks(&options.excluded_tracks, metadata);
+    let included_tracks = resolve_tracks(&options.included_tracks, metadata);
+
     let tracks = tracks.difference(&excluded_tracks)
         .map(|v| *v)
         .collect::<HashSet<usize>>();
@@ -134,17 +1506,33 @@ pub fn server(matches: &ArgMatches) {
         .collect::<HashSet<usize>>();
 
     println!("tracks:");
-    println!("  pre  filter: {:?}", tracks_before_filtering.iter().sorted());
-    println!("  post filter: {:?}", tracks.iter().sorted());
+    println!("  pre  filter: [{}]", describe_tracks(&tracks_before_filtering, metadata));
+    println!("  post filter: [{}]", describe_tracks(&tracks, metadata));
     println!("channels:");
     println!("  pre  filter: {:?}", channels_before_filtering.iter().sorted());
     println!("  post filter: {:?}", channels.iter().sorted());
 
-    let events_to_play = music.events().iter()
+    let instruments = music.events().iter()
+        .filter_map(|e| {
+            if let &MusicalEvent::PlayNote(Note { channel, program, instrument, .. }) = e {
+                Some(((channel, program), instrument))
+            } else {
+                None
+            }
+        })
+        .collect::<BTreeMap<_, _>>();
+    println!("instruments:");
+    for (&(channel, program), instrument) in instruments.iter() {
+        // programs are shown counting from 1, as they're usually listed
+        println!("  channel {}: program {} ({:?})", channel, program as u32 + 1, instrument);
+    }
+
+    let events = music.events().iter()
         .map(|e| e.clone())
         .filter(|e| {
             match e {
-                MusicalEvent::PlayNote(Note { track, channel, .. }) => {
+                MusicalEvent::PlayNote(Note { track, channel, .. }) |
+                MusicalEvent::PitchBend(PitchBend { track, channel, .. }) => {
                     tracks.contains(track) && channels.contains(channel)
                 },
                 _ => true,
@@ -152,155 +1540,320 @@ pub fn server(matches: &ArgMatches) {
         })
         .collect::<Vec<_>>();
 
-    let policy = select_policy(policy_name.to_string(), &events_to_play)
-        .expect("invalid policy");
-    println!("client selection policy: {}", policy_name);
+    let events = midi::scale_tempo(&events, options.tempo);
+    let events = midi::transpose(&events, options.transpose);
 
-    let shared_state_original = Arc::new(Mutex::new(
-        SharedState::new(music.events().len() as u64, policy)
-    ));
+    // positions are found after changing the tempo, so that bars still land where they should
+    let start = options.start_at
+        .map(|position| midi::position_to_offset(&events, position))
+        .unwrap_or(Duration::new(0, 0));
+    let end = options.end_at
+        .map(|position| midi::position_to_offset(&events, position));
+    let events = midi::slice(&events, start, end);
+    let end = end.unwrap_or_else(|| midi::end_offset(&events));
 
-    let shared_state = shared_state_original.clone();
-    spawn(move || {
-        println!("accepting client connections...");
-        let mut client_uid_factory = ClientUIDFactory::new();
-        for stream in listener.incoming() {
-            match stream {
-                Ok(s) => {
-                    let mut state = shared_state_original.lock()
-                        .expect("failed to acquire mutex while accepting");
-                    let mut connection = Connection::new(
-                        s,
-                        ClientInfo::new(client_uid_factory.make())
-                    );
-
-                    let info = connection.recv()
-                        .expect("failed to receive client info packet");
-                    let okay = match info {
-                        Packet::ClientInfo => {
-                            true
-                        },
-                        _ => false,
-                    };
-
-                    if okay {
-                        state.print_before(&format!("connection accepted: {:?}", connection.info.uid));
-                        state.connections.push(connection);
-                        let clients_info = state.connections.iter()
-                            .map(|c| c.info.clone())
-                            .collect::<Vec<_>>();
-                        state.policy.on_clients_changed(&clients_info);
-                    } else {
-                        state.print_before("connection rejected");
-                        connection.send(Packet::TerminateAfter(0))
-                            .expect("failed to send rejection termination");
-                        connection.stream.flush()
-                            .expect("failed to flush rejected connection");
-                        connection.stream.shutdown(std::net::Shutdown::Both)
-                            .expect("failed to shutdown rejected connection");
+    if end <= start {
+        println!("warning: the song ends before the start position, there's nothing to play");
+    } else if options.start_at.is_some() || options.end_at.is_some() {
+        println!("playing from {:.1}s to {:.1}s", duration_to_seconds(start), duration_to_seconds(end));
+    }
+
+    Ok(Song {
+        name: path.display().to_string(),
+        events,
+        start,
+        end,
+    })
+}
+
+/// Finds the first event at or after the given offset.
+fn first_event_from(events: &[MusicalEvent], offset: Duration) -> usize {
+    events.iter()
+        .position(|event| event.start_offset() >= offset)
+        .unwrap_or(events.len())
+}
+
+/// Where notes are batched, finds the start of the lookahead window the given offset falls in.
+fn window_start(offset: Duration, lookahead: Option<Duration>) -> Duration {
+    match lookahead {
+        Some(lookahead) => {
+            let offset = duration_to_nanoseconds(offset);
+            let lookahead = duration_to_nanoseconds(lookahead);
+            nanoseconds_to_duration(offset - (offset % lookahead))
+        },
+        None => offset,
+    }
+}
+
+fn queue_batches(batches: &mut HashMap<ClientUID, Vec<BatchedNote>>, window: Instant, state: &SharedState, sends: &mut Schedule<(Vec<ClientUID>, Packet)>, start_time: u64) {
+    for (uid, notes) in batches.drain() {
+        sends.push(window - state.one_way_latency(uid), (vec![uid], Packet::NoteBatch {
+            start_time,
+            notes,
+        }));
+    }
+}
+
+/// Queues a bend for every channel, putting each how it is at the given offset into the song, and
+/// straightening those the song doesn't bend.
+fn queue_bends_at(sends: &mut Schedule<(Vec<ClientUID>, Packet)>, state: &SharedState, events: &[MusicalEvent], offset: Duration, start_time: Instant, lead: Duration, clock: Clock, grouped: bool) {
+    let event_time = start_time + offset;
+
+    let mut bends = (1..17)
+        .map(|channel| (channel, 0.0))
+        .collect::<BTreeMap<u8, f32>>();
+    bends.extend(midi::bends_at(events, offset));
+
+    for (channel, semitones) in bends {
+        let everyone = state.connections.iter()
+            .map(|c| c.info.uid)
+            .collect();
+        queue_send(sends, state, everyone, event_time, grouped, Packet::PitchBend {
+            start_time: clock.timestamp(event_time + lead),
+            channel,
+            semitones,
+        });
+    }
+}
+
+/// Queues a packet due at the given time, sending it to each client early by that client's own
+/// latency. When grouped, e.g. for multicast, it's instead sent once to every client, early enough
+/// for the slowest of them.
+fn queue_send(sends: &mut Schedule<(Vec<ClientUID>, Packet)>, state: &SharedState, targets: Vec<ClientUID>, due: Instant, grouped: bool, packet: Packet) {
+    if grouped {
+        let latency = targets.iter()
+            .map(|uid| state.one_way_latency(*uid))
+            .max();
+
+        if let Some(latency) = latency {
+            sends.push(due - latency, (targets, packet));
+        }
+    } else {
+        for uid in targets {
+            sends.push(due - state.one_way_latency(uid), (vec![uid], packet.clone()));
+        }
+    }
+}
+
+/// Sends every packet that has fallen due, evicting clients that can no longer be sent to.
+fn send_due(sends: &mut Schedule<(Vec<ClientUID>, Packet)>, state: &mut SharedState, clock: Clock, multicast_sender: &Option<MulticastSender>, recorder: &Option<Recorder>) {
+    state.send_held_note_offs();
+
+    while let Some((due, (targets, packet))) = sends.pop_due_at(Instant::now()) {
+        let scheduled = clock.timestamp(due);
+        if let Packet::PitchBend { channel, .. } = packet {
+            state.bends.insert(channel, packet.clone());
+        }
+        let note_off = match packet {
+            Packet::NoteOff { .. } => true,
+            _ => false,
+        };
+
+        // a lost datagram can be shrugged off for most packets, but not for a note's only end
+        if let (Some(ref sender), false) = (multicast_sender, note_off) {
+            match sender.send(targets.clone(), packet.clone()) {
+                Ok(_) => {
+                    if let Some(ref recorder) = *recorder {
+                        recorder.record(targets, Some(scheduled), &packet);
                     }
+                    continue;
                 },
-                Err(e) => panic!("IO error while listening: {}", e),
+                // e.g. a big batch, or one addressed to many clients, which each client is sent directly
+                Err(MulticastError::TooLarge { .. }) => {},
+                Err(e) => state.print_before(&format!("warning: failed to multicast packet, sending it directly: {}", e)),
             }
         }
-    });
 
-    let delay_period = Duration::from_secs(5);
-    println!("waiting {} seconds for clients to connect...", duration_to_seconds(delay_period));
-    sleep(delay_period);
+        for uid in targets {
+            let result = state.connections.iter()
+                .find(|c| c.info.uid == uid)
+                .map(|connection| connection.send_scheduled(packet.clone(), scheduled));
 
-    println!("starting playback!");
-    let mut latest_note_end_time = Instant::now();
-    let start_time = Instant::now();
-    for event in events_to_play.iter() {
-        let start_offset = match event {
-            MusicalEvent::PlayNote(Note { start_offset, .. }) => start_offset,
-            MusicalEvent::TimingChange(TimingChange { start_offset, .. }) => start_offset,
-        };
+            match result {
+                Some(Err(SendError::QueueFull)) if note_off => state.hold_note_off(uid, packet.clone(), scheduled),
+                Some(Err(SendError::QueueFull)) => state.overflowed(uid),
+                Some(Err(SendError::Failed(reason))) => {
+                    state.evict(uid, &format!("failed to send note: {}", reason));
+                },
+                _ => {},
+            }
+        }
+    }
+}
 
-        let now = Instant::now();
-        let event_time = start_time + *start_offset;
+fn read_client_packets(connection: &Connection, shared_state: Arc<Mutex<SharedState>>, clock: Clock, codec: Codec) {
+    let uid = connection.info.uid;
+    let stream = connection.stream.try_clone()
+        .expect("failed to clone client stream for reading");
 
-        if now < event_time {
-            let time_until_note = event_time - now;
-            {
-                let mut state = shared_state.lock()
-                    .expect("failed to acquire mutex to show sleep time");
-                state.progress_bar.message(&format!("sleep: {:04}ms: ", (duration_to_seconds(time_until_note) * 1000f64) as u64));
+    spawn(move || {
+        loop {
+            let result = codec.recv(&stream);
+
+            let mut state = shared_state.lock()
+                .expect("failed to acquire mutex to handle client packet");
+
+            let packet = match result {
+                Ok(packet) => packet,
+                Err(e) => {
+                    state.evict(uid, &format!("failed to receive: {}", e));
+                    break;
+                },
+            };
+
+            state.heard_from(uid);
+
+            match packet {
+                Packet::Pong { server_time } => {
+                    state.record_round_trip(uid, clock.now().saturating_sub(server_time));
+                },
+                packet => {
+                    state.print_before(&format!("unexpected packet from {:?}: {:?}", uid, packet));
+                },
             }
-            sleep(time_until_note);
         }
+    });
+}
 
-        match event {
-            MusicalEvent::PlayNote(note) => {
-                let midi_note = Step(note.note as f32);
-                let volume = (note.velocity as f32 / 128.0) * volume_coefficient;
-                let end_time = now + note.duration;
-                if end_time >= latest_note_end_time {
-                    latest_note_end_time = end_time;
-                }
+fn handshake(stream: TcpStream, uid: ClientUID, clock: &Clock, settings: &ConnectionSettings) -> Result<Connection, String> {
+    let codec = settings.codec;
+    let deadline = Instant::now() + HANDSHAKE_TIMEOUT;
+    stream.set_read_timeout(Some(HANDSHAKE_TIMEOUT))
+        .map_err(|e| format!("failed to set handshake timeout: {}", e))?;
+
+    let info = match codec.recv(&stream) {
+        Ok(Packet::ClientInfo { protocol_version, name, capabilities }) => {
+            ClientInfo::new(uid, protocol_version, name, capabilities)
+        },
+        Ok(packet) => return reject(stream, uid, settings, format!("expected client info but received {:?}", packet)),
+        Err(e) => return Err(format!("failed to receive client info: {}", e)),
+    };
 
-                let state = shared_state.lock()
-                    .expect("failed to lock mutex to send note");
+    if info.protocol_version != PROTOCOL_VERSION {
+        return reject(stream, uid, settings, format!(
+            "client speaks protocol version {} but server speaks version {}",
+            info.protocol_version,
+            PROTOCOL_VERSION
+        ));
+    }
 
-                let assigned_connections = state.policy.select_clients(&note);
+    if info.capabilities.waveforms.contains(&Waveform::Square) == false {
+        return reject(stream, uid, settings, "client cannot play square waves".into());
+    }
 
-                for connection in state.connections.iter() {
-                    if assigned_connections.contains(&connection.info.uid) {
-                        connection.send(Packet::PlayNote {
-                            duration: duration_to_nanoseconds(note.duration),
-                            frequency: midi_note.to_hz().0,
-                            volume,
-                        }).expect("failed to send note packet");
-                    }
-                }
-            },
+    if info.capabilities.max_polyphony == 0 {
+        return reject(stream, uid, settings, "client cannot play any notes at once".into());
+    }
 
-            MusicalEvent::TimingChange(_timing_change) => {
-                // we could emit timing information here, but we won't :)
-            },
-        }
+    send_directly(&stream, uid, settings, &Packet::Accept { uid })
+        .map_err(|e| format!("failed to send acceptance: {}", e))?;
 
-        {
-            let mut state = shared_state.lock()
-                .expect("failed to acquire mutex to update progress bar");
-            state.progress_bar.inc();
-        }
+    if serve_clock_sync(&stream, uid, clock, settings, deadline) == false {
+        return Err("clock sync failed".into());
     }
 
-    let mut state = shared_state.lock()
-        .expect("failed to lock mutex for shutdown processes");
+    if let Some(group) = settings.multicast {
+        send_directly(&stream, uid, settings, &Packet::UseMulticast { group: group.to_string() })
+            .map_err(|e| format!("failed to send multicast group: {}", e))?;
+    }
 
-    state.progress_bar.finish_println("playback complete\n");
+    stream.set_read_timeout(None)
+        .map_err(|e| format!("failed to clear handshake timeout: {}", e))?;
 
-    let now = Instant::now();
-    let terminate_delay = if now < latest_note_end_time {
-        duration_to_nanoseconds(latest_note_end_time - now)
-    } else {
-        0
-    };
+    Ok(Connection::new(stream, info, codec, settings.queue_size, settings.recorder.clone()))
+}
 
-    println!("telling clients to terminate...");
-    for client in state.connections.iter_mut() {
-        client.send( Packet::TerminateAfter(
-            terminate_delay
-        )).expect("failed to serialize termination packet");
+/// Sends a packet straight down a stream that hasn't become a `Connection` yet.
+fn send_directly(stream: &TcpStream, uid: ClientUID, settings: &ConnectionSettings, packet: &Packet) -> Result<(), FrameError> {
+    settings.codec.send(stream, packet)?;
+
+    if let Some(ref recorder) = settings.recorder {
+        recorder.record(vec![uid], None, packet);
     }
+    Ok(())
+}
 
-    println!("ensuring clients get termination messages...");
-    for client in state.connections.iter_mut() {
-        client.stream.flush()
-            .expect("failed to flush rejected connection");
-        client.stream.shutdown(std::net::Shutdown::Both)
-            .expect("failed to shutdown client during termination");
+fn reject(mut stream: TcpStream, uid: ClientUID, settings: &ConnectionSettings, reason: String) -> Result<Connection, String> {
+    // the client may already be gone, in which case there's nobody to tell
+    send_directly(&stream, uid, settings, &Packet::Reject { reason: reason.clone() }).ok();
+    stream.flush().ok();
+    stream.shutdown(Shutdown::Both).ok();
+
+    Err(reason)
+}
+
+fn serve_clock_sync(stream: &TcpStream, uid: ClientUID, clock: &Clock, settings: &ConnectionSettings, deadline: Instant) -> bool {
+    loop {
+        if Instant::now() > deadline {
+            return false;
+        }
+
+        let packet = match settings.codec.recv(stream) {
+            Ok(packet) => packet,
+            Err(_) => return false,
+        };
+        let server_receive_time = clock.now();
+
+        match packet {
+            Packet::ClockSyncRequest { client_send_time } => {
+                let response = Packet::ClockSyncResponse {
+                    client_send_time,
+                    server_receive_time,
+                    server_send_time: clock.now(),
+                };
+                if send_directly(stream, uid, settings, &response).is_err() {
+                    return false;
+                }
+            },
+            Packet::ClockSyncComplete => return true,
+            _ => return false,
+        }
     }
+}
 
-    // better safe than sorry!
-    if terminate_delay > 0 {
-        sleep(nanoseconds_to_duration(terminate_delay));
+/// Reads a list of tracks given by number or by name, see `resolve_tracks`.
+fn track_list_to_hashset(matches: &ArgMatches, name: &str) -> HashSet<String> {
+    let result = matches.values_of(name)
+        .map(|values| values.map(|track| track.to_string()).collect::<HashSet<_>>())
+        .unwrap_or_else(|| HashSet::new());
+
+    if result.len() > 0 {
+        println!("{}: {:?}", name, result);
     }
 
-    println!("done");
+    result
+}
+
+/// Finds the tracks given by number or by name, warning about names no track has.
+fn resolve_tracks(tracks: &HashSet<String>, metadata: &Metadata) -> HashSet<usize> {
+    let mut resolved = HashSet::new();
+
+    for track in tracks.iter() {
+        if let Ok(number) = track.parse::<usize>() {
+            resolved.insert(number);
+            continue;
+        }
+
+        let named = metadata.tracks_named(track);
+        if named.is_empty() {
+            println!("warning: no track is named \"{}\"", track);
+        }
+        resolved.extend(named);
+    }
+
+    resolved
+}
+
+/// Lists track numbers in order, each followed by its name if it has one.
+fn describe_tracks(tracks: &HashSet<usize>, metadata: &Metadata) -> String {
+    tracks.iter()
+        .sorted()
+        .into_iter()
+        .map(|track| match metadata.track_names.get(track) {
+            Some(name) => format!("{} \"{}\"", track, name),
+            None => track.to_string(),
+        })
+        .join(", ")
 }
 
 fn number_list_to_hashset<T>(matches: &ArgMatches, name: &str, kind: &str) -> HashSet<T>
@@ -0,0 +1,210 @@
+use connection::{ClientUID, ClientUIDFactory, ClientInfo};
+use server::{parse_song_options, load_song};
+use packet::{Capabilities, Waveform, PROTOCOL_VERSION};
+use policies::select_policy;
+use midi::{MusicalEvent, Note};
+use convert_duration::*;
+
+use std::collections::HashMap;
+use std::time::Duration;
+use std::path::Path;
+use std::process;
+use std::cmp::{min, max};
+
+use pitch_calc::{Step, LetterOctave};
+use clap::ArgMatches;
+
+/// What a virtual client would have been asked to play.
+struct ClientStatistics {
+    /// (start, end) of every note, in the order they started.
+    notes: Vec<(Duration, Duration)>,
+    lowest: u8,
+    highest: u8,
+}
+
+impl ClientStatistics {
+    fn new() -> Self {
+        Self {
+            notes: Vec::new(),
+            lowest: u8::max_value(),
+            highest: u8::min_value(),
+        }
+    }
+
+    fn add(&mut self, note: &Note) {
+        self.notes.push((note.start_offset, note.start_offset + note.duration));
+        self.lowest = min(self.lowest, note.note);
+        self.highest = max(self.highest, note.note);
+    }
+
+    /// How long at least one note is sounding, overlapping notes only counting once.
+    fn busy_time(&self) -> Duration {
+        let mut busy = Duration::new(0, 0);
+        let mut current: Option<(Duration, Duration)> = None;
+
+        for &(start, end) in self.notes.iter() {
+            current = match current {
+                Some((current_start, current_end)) if start <= current_end => {
+                    Some((current_start, max(current_end, end)))
+                },
+                Some((current_start, current_end)) => {
+                    busy += current_end - current_start;
+                    Some((start, end))
+                },
+                None => Some((start, end)),
+            };
+        }
+
+        if let Some((start, end)) = current {
+            busy += end - start;
+        }
+
+        busy
+    }
+
+    fn max_simultaneous(&self) -> usize {
+        let mut changes = self.notes.iter()
+            .flat_map(|&(start, end)| vec![(start, 1i64), (end, -1i64)])
+            .collect::<Vec<_>>();
+        // notes ending as others start don't overlap, so ends come first
+        changes.sort();
+
+        let mut sounding = 0;
+        let mut most = 0;
+        for (_, change) in changes {
+            sounding += change;
+            most = max(most, sounding);
+        }
+
+        most as usize
+    }
+}
+
+/// Runs a song through a policy with pretend clients, reporting what each would have played.
+pub fn simulate(matches: &ArgMatches) {
+    let path = matches.value_of("midi").unwrap();
+    let verbose = matches.is_present("verbose");
+    let policy_name = matches.value_of("policy").unwrap();
+    let client_count: usize = match matches.value_of("clients").unwrap().parse() {
+        Ok(value) if value > 0 => value,
+        _ => {
+            println!("invalid client count, must be a whole number above zero");
+            return;
+        },
+    };
+    let max_polyphony: u32 = match matches.value_of("max polyphony").unwrap().parse() {
+        Ok(value) => value,
+        Err(_) => {
+            println!("invalid max polyphony value, must be a whole number");
+            return;
+        },
+    };
+    let song_options = match parse_song_options(matches) {
+        Ok(options) => options,
+        Err(e) => {
+            println!("{}", e);
+            return;
+        },
+    };
+
+    let song = match load_song(Path::new(path), &song_options, verbose) {
+        Ok(song) => song,
+        Err(e) => {
+            println!("unable to load {}: {}", path, e);
+            process::exit(1);
+        },
+    };
+
+    let mut policy = select_policy(policy_name.to_string(), &song.events)
+        .expect("invalid policy");
+    println!("client selection policy: {}", policy_name);
+
+    let clients = virtual_clients(client_count, max_polyphony);
+    policy.on_clients_changed(&clients);
+
+    for assignment in policy.assignments() {
+        let names = assignment.clients.iter()
+            .filter_map(|uid| clients.iter().find(|c| c.uid == *uid))
+            .map(|c| c.name.as_str())
+            .collect::<Vec<_>>();
+        println!("  {} => {}", assignment.part, names.join(", "));
+    }
+
+    let mut statistics: HashMap<ClientUID, ClientStatistics> = HashMap::new();
+    let mut unassigned = 0;
+    let mut total = 0;
+
+    for event in song.events.iter() {
+        if let MusicalEvent::PlayNote(note) = event {
+            total += 1;
+
+            let targets = policy.select_clients(note);
+            if targets.is_empty() {
+                unassigned += 1;
+            }
+
+            for uid in targets {
+                statistics.entry(uid)
+                    .or_insert_with(ClientStatistics::new)
+                    .add(note);
+            }
+        }
+    }
+
+    let length = if song.end > song.start { song.end - song.start } else { Duration::new(0, 0) };
+    println!();
+    println!("{} note(s) over {:.1}s, {} with no client to play them", total, duration_to_seconds(length), unassigned);
+    println!("{:<12} {:>6} {:>9} {:>6} {:>5}  {}", "client", "notes", "busy", "busy%", "max", "range");
+
+    for client in clients.iter() {
+        let name = &client.name;
+        match statistics.get(&client.uid) {
+            Some(stats) => {
+                let busy = stats.busy_time();
+                let busy_percentage = if length > Duration::new(0, 0) {
+                    100.0 * duration_to_seconds(busy) / duration_to_seconds(length)
+                } else {
+                    0.0
+                };
+                let most = stats.max_simultaneous();
+                let warning = if most > max_polyphony as usize { " (exceeds max polyphony!)" } else { "" };
+
+                println!(
+                    "{:<12} {:>6} {:>8.1}s {:>5.0}% {:>5}  {} - {}{}",
+                    name,
+                    stats.notes.len(),
+                    duration_to_seconds(busy),
+                    busy_percentage,
+                    most,
+                    describe_note(stats.lowest),
+                    describe_note(stats.highest),
+                    warning
+                );
+            },
+            None => println!("{:<12} {:>6}", name, 0),
+        }
+    }
+}
+
+/// Pretend clients named `virtual-1` onwards, able to play anything audible.
+pub fn virtual_clients(count: usize, max_polyphony: u32) -> Vec<ClientInfo> {
+    let mut client_uid_factory = ClientUIDFactory::new();
+    (0..count)
+        .map(|index| ClientInfo::new(
+            client_uid_factory.make(),
+            PROTOCOL_VERSION,
+            format!("virtual-{}", index + 1),
+            Capabilities {
+                max_polyphony,
+                waveforms: vec![Waveform::Square],
+                lowest_frequency: 20.0,
+                highest_frequency: 20000.0,
+            },
+        ))
+        .collect()
+}
+
+fn describe_note(note: u8) -> String {
+    let LetterOctave(letter, octave) = Step(note as f32).to_letter_octave();
+    format!("{:?}{} ({})", letter, octave, note)
+}
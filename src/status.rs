@@ -0,0 +1,65 @@
+use std::io::{BufRead, BufReader, Write};
+use std::net::{TcpListener, TcpStream};
+use std::time::Duration;
+use std::thread::spawn;
+use std::sync::Arc;
+use std::io;
+
+/// How long a dashboard has to send its request before it's given up on.
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// Answers `GET /status` on the given local port with whatever JSON `report` produces at the time.
+pub fn serve<F>(port: u16, report: F) -> io::Result<()>
+    where F: Fn() -> String + Send + Sync + 'static {
+    // only reachable from this machine, dashboards elsewhere can go through a proxy
+    let listener = TcpListener::bind(("127.0.0.1", port))?;
+    let report = Arc::new(report);
+
+    spawn(move || {
+        for stream in listener.incoming() {
+            if let Ok(stream) = stream {
+                let report = report.clone();
+                spawn(move || {
+                    // there's nothing useful to do about a dashboard that went away mid-request
+                    respond(stream, &*report).ok();
+                });
+            }
+        }
+    });
+
+    Ok(())
+}
+
+fn respond<F: Fn() -> String>(stream: TcpStream, report: &F) -> io::Result<()> {
+    stream.set_read_timeout(Some(REQUEST_TIMEOUT))?;
+    let mut writer = stream.try_clone()?;
+    let mut reader = BufReader::new(stream);
+
+    let mut request_line = String::new();
+    reader.read_line(&mut request_line)?;
+
+    // the headers don't matter, but are read so that the client sees a clean close
+    loop {
+        let mut header = String::new();
+        if reader.read_line(&mut header)? == 0 || header.trim().is_empty() {
+            break;
+        }
+    }
+
+    let mut parts = request_line.split_whitespace();
+    let (status, body) = match (parts.next(), parts.next()) {
+        (Some("GET"), Some("/")) | (Some("GET"), Some("/status")) => ("200 OK", report()),
+        (Some("GET"), Some(_)) => ("404 Not Found", "{\"error\":\"not found\"}".to_string()),
+        _ => ("405 Method Not Allowed", "{\"error\":\"only GET is supported\"}".to_string()),
+    };
+
+    // no CORS headers, so that web pages open on this machine can't read client names and addresses
+    write!(
+        writer,
+        "HTTP/1.1 {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status,
+        body.len(),
+        body
+    )?;
+    writer.flush()
+}